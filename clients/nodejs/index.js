@@ -23,10 +23,10 @@ function sendCommand(command) {
 }
 
 const commands = [
-  "GEOADD location1 37.7749 -122.4194\n",
-  "GEOADD location2 34.0522 -118.2437\n",
-  "GEOSEARCH 37.7749 -122.4194 500000\n",
-  "GEOGET location1",
+  "GEOADD locations location1 37.7749 -122.4194\n",
+  "GEOADD locations location2 34.0522 -118.2437\n",
+  "GEOSEARCH locations 37.7749 -122.4194 500000\n",
+  "GEOGET locations location1\n",
 ];
 
 commands.forEach((command) => sendCommand(command));
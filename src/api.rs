@@ -1,70 +1,1248 @@
-use crate::storage::GeoDatabase;
+use crate::collections::{unshare, GeoCollections};
+use crate::geospatial::{
+    geohash_cell, geometry_from_coords, geometry_from_geohash, geometry_from_wkt, geometry_geohash,
+    geometry_position, geometry_type, polygon_from_lat_lon, polygon_from_wkt, AxisOrder,
+    DistanceMethod, DistanceUnit, DEFAULT_GEOHASH_PRECISION,
+};
+use crate::h3::{MAX_GRID_DISK_K, NO_H3_INDEX, NO_RESOLUTION};
+use crate::persistence::{self, Persistence, WalEntry};
+use crate::properties::{
+    properties_from_map, properties_to_json, CompareOp, Filter as PropertyFilter,
+};
+use crate::storage::{
+    expiry_deadline, validate_box, validate_lat_lon, validate_polygon, AddCondition, AddOutcome,
+    AreaMatch, BoxMatch, GeoDatabase, KeyTtl, SearchHit, SearchOptions, SortOrder,
+    INVALID_EXPIRE_TIME,
+};
+use h3o::{CellIndex, Resolution};
+use log::error;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::io;
 use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
 use warp::Filter;
 
+/// `?axis=LONLAT` makes a request's coordinate pairs and WKT read as
+/// `(lon, lat)`; without it they are `(lat, lon)`.
+#[derive(Debug, Deserialize, Serialize)]
+struct AxisQuery {
+    #[serde(default)]
+    axis: AxisOrder,
+}
+
+/// The geometry is given either as `(lat, lon)` pairs (one pair for a point,
+/// more for a polygon ring), as WKT, which names its type explicitly, or as a
+/// geohash, which stands for the point at the centre of its cell. With
+/// `expire_seconds` the key expires that many seconds from now.
 #[derive(Debug, Deserialize, Serialize)]
 struct GeoAddRequest {
     key: String,
-    coords: Vec<(f64, f64)>,
+    #[serde(default)]
+    coords: Option<Vec<(f64, f64)>>,
+    #[serde(default)]
+    wkt: Option<String>,
+    #[serde(default)]
+    geohash: Option<String>,
+    #[serde(default)]
+    condition: Option<AddCondition>,
+    #[serde(default)]
+    properties: Option<Map<String, Value>>,
+    #[serde(default)]
+    expire_seconds: Option<u64>,
+}
+
+/// `?precision=n` asks for an `n` character geohash, 11 by default.
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoHashQuery {
+    #[serde(default = "default_geohash_precision")]
+    precision: usize,
+}
+
+fn default_geohash_precision() -> usize {
+    DEFAULT_GEOHASH_PRECISION
+}
+
+/// `?resolution=n` picks the H3 resolution; without it the collection's H3
+/// index decides.
+#[derive(Debug, Deserialize, Serialize)]
+struct H3ResolutionQuery {
+    #[serde(default)]
+    resolution: Option<u8>,
+}
+
+/// `?k=n` widens an H3 cell lookup to the cells up to `n` steps away.
+#[derive(Debug, Deserialize, Serialize)]
+struct H3KeysQuery {
+    #[serde(default)]
+    k: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoExpireRequest {
+    seconds: u64,
 }
 
+/// With any of the `with_*` flags the reply lists `{"key": ...}` objects
+/// instead of bare keys, adding `distance`, `coordinates` (a point's own
+/// pair or its bounding box corners, in `?axis` order), `type` and
+/// `properties` as asked.
+/// `sort`, `count` and `any` work like `ASC`/`DESC` and `COUNT n [ANY]` over
+/// TCP. Every entry in `filters` has to hold. `radius` and the reported
+/// distances are in `unit`, metres by default.
 #[derive(Debug, Deserialize, Serialize)]
 struct GeoSearchRequest {
     lat: f64,
     lon: f64,
     radius: f64,
+    #[serde(default)]
+    unit: DistanceUnit,
+    #[serde(default)]
+    with_dist: bool,
+    #[serde(default)]
+    with_coord: bool,
+    #[serde(default)]
+    with_type: bool,
+    #[serde(default)]
+    with_props: bool,
+    #[serde(default)]
+    sort: Option<SortOrder>,
+    #[serde(default)]
+    count: Option<usize>,
+    #[serde(default)]
+    any: bool,
+    #[serde(default)]
+    filters: Vec<FilterRequest>,
 }
 
+/// A property condition such as `{"field": "rating", "op": ">=", "value": 4}`.
+#[derive(Debug, Deserialize, Serialize)]
+struct FilterRequest {
+    field: String,
+    op: CompareOp,
+    value: Value,
+}
 
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoSearchBoxRequest {
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+    #[serde(default)]
+    mode: BoxMatch,
+}
+
+/// A query polygon, given either as a ring of `(lat, lon)` pairs or as WKT.
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoAreaRequest {
+    #[serde(default)]
+    coords: Option<Vec<(f64, f64)>>,
+    #[serde(default)]
+    wkt: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoContainingRequest {
+    lat: f64,
+    lon: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoContainingBatchRequest {
+    points: Vec<(f64, f64)>,
+}
+
+/// `max_distance` and the reported distances are in `unit`, metres by default.
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoNearestRequest {
+    lat: f64,
+    lon: f64,
+    k: usize,
+    #[serde(default)]
+    max_distance: Option<f64>,
+    #[serde(default)]
+    unit: DistanceUnit,
+}
+
+/// The reported distance is in `unit`, metres by default.
+#[derive(Debug, Deserialize, Serialize)]
+struct GeoDistRequest {
+    key1: String,
+    key2: String,
+    #[serde(default)]
+    unit: DistanceUnit,
+    #[serde(default)]
+    method: DistanceMethod,
+}
+
+/// Builds the REST routes. Everything that touches data lives under
+/// `/collections/{collection}/...`; a collection that does not exist gets a
+/// 404, except for `geoadd`, which creates it.
 pub fn create_api(
-    db: Arc<Mutex<GeoDatabase>>,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let create_collection = warp::post()
+        .and(warp::path!("collections" / String))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_create_collection);
+
+    let drop_collection = warp::delete()
+        .and(warp::path!("collections" / String))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_drop_collection);
+
+    let list_collections = warp::get()
+        .and(warp::path!("collections"))
+        .and(with_collections(collections.clone()))
+        .and_then(handle_list_collections);
+
     let geoadd = warp::post()
-        .and(warp::path("geoadd"))
+        .and(warp::path!("collections" / String / "geoadd"))
+        .and(warp::query::<AxisQuery>())
         .and(warp::body::json())
-        .and(with_db(db.clone()))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
         .and_then(handle_geoadd);
 
     let geosearch = warp::post()
-        .and(warp::path("geosearch"))
+        .and(warp::path!("collections" / String / "geosearch"))
+        .and(warp::query::<AxisQuery>())
         .and(warp::body::json())
-        .and(with_db(db.clone()))
+        .and(with_collections(collections.clone()))
         .and_then(handle_geosearch);
 
+    let geosearchbox = warp::post()
+        .and(warp::path!("collections" / String / "geosearchbox"))
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geosearchbox);
+
+    let geosearchhash = warp::get()
+        .and(warp::path!(
+            "collections" / String / "geosearchhash" / String
+        ))
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geosearchhash);
+
+    let geowithin = warp::post()
+        .and(warp::path!("collections" / String / "geowithin"))
+        .and(warp::query::<AxisQuery>())
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(|collection, query, body, collections| {
+            handle_geosearch_area(collection, query, body, collections, AreaMatch::Within)
+        });
+
+    let geointersects = warp::post()
+        .and(warp::path!("collections" / String / "geointersects"))
+        .and(warp::query::<AxisQuery>())
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(|collection, query, body, collections| {
+            handle_geosearch_area(collection, query, body, collections, AreaMatch::Intersects)
+        });
+
+    let geocontains = warp::post()
+        .and(warp::path!("collections" / String / "geocontains"))
+        .and(warp::query::<AxisQuery>())
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(|collection, query, body, collections| {
+            handle_geosearch_area(collection, query, body, collections, AreaMatch::Contains)
+        });
+
+    let geocontaining = warp::post()
+        .and(warp::path!("collections" / String / "geocontaining"))
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geocontaining);
+
+    let geocontaining_batch = warp::post()
+        .and(warp::path!(
+            "collections" / String / "geocontaining" / "batch"
+        ))
+        .and(warp::query::<AxisQuery>())
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geocontaining_batch);
+
+    let geonearest = warp::post()
+        .and(warp::path!("collections" / String / "geonearest"))
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geonearest);
+
+    let geodist = warp::post()
+        .and(warp::path!("collections" / String / "geodist"))
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geodist);
+
+    let geoget = warp::get()
+        .and(warp::path!("collections" / String / "geo" / String))
+        .and(warp::query::<AxisQuery>())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geoget);
+
+    let geosetprops = warp::put()
+        .and(warp::path!(
+            "collections" / String / "geo" / String / "props"
+        ))
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_geosetprops);
+
+    let geoexpire = warp::post()
+        .and(warp::path!(
+            "collections" / String / "geo" / String / "expire"
+        ))
+        .and(warp::body::json())
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_geoexpire);
+
+    let geohash = warp::get()
+        .and(warp::path!(
+            "collections" / String / "geo" / String / "geohash"
+        ))
+        .and(warp::query::<GeoHashQuery>())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geohash);
+
+    let geottl = warp::get()
+        .and(warp::path!("collections" / String / "geo" / String / "ttl"))
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geottl);
+
+    let create_index = warp::post()
+        .and(warp::path!("collections" / String / "index" / String))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_create_index);
+
+    let drop_index = warp::delete()
+        .and(warp::path!("collections" / String / "index" / String))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_drop_index);
+
+    let create_h3_index = warp::post()
+        .and(warp::path!("collections" / String / "h3index" / u8))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_create_h3_index);
+
+    let drop_h3_index = warp::delete()
+        .and(warp::path!("collections" / String / "h3index"))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_drop_h3_index);
+
+    let geoh3cell = warp::get()
+        .and(warp::path!("collections" / String / "geo" / String / "h3"))
+        .and(warp::query::<H3ResolutionQuery>())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geoh3cell);
+
+    let geoh3keys = warp::get()
+        .and(warp::path!("collections" / String / "h3" / String))
+        .and(warp::query::<H3KeysQuery>())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geoh3keys);
+
+    let geoh3polyfill = warp::get()
+        .and(warp::path!(
+            "collections" / String / "geo" / String / "polyfill"
+        ))
+        .and(warp::query::<H3ResolutionQuery>())
+        .and(with_collections(collections.clone()))
+        .and_then(handle_geoh3polyfill);
+
+    let list_indexes = warp::get()
+        .and(warp::path!("collections" / String / "index"))
+        .and(with_collections(collections.clone()))
+        .and_then(handle_list_indexes);
+
+    let geodel = warp::delete()
+        .and(warp::path!("collections" / String / "geo" / String))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_geodel);
+
+    let save = warp::post()
+        .and(warp::path!("save"))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_save);
+
+    let bgsave = warp::post()
+        .and(warp::path!("bgsave"))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_bgsave);
+
+    let lastsave = warp::get()
+        .and(warp::path!("lastsave"))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_lastsave);
+
+    let info = warp::get()
+        .and(warp::path!("info"))
+        .and(with_collections(collections.clone()))
+        .and(with_persistence(persistence.clone()))
+        .and_then(handle_info);
 
     let health = warp::get()
         .and(warp::path("health"))
         .and_then(handle_health);
 
-    geoadd.or(geosearch).or(health)
+    // Boxed so the route type stays shallow enough for release builds.
+    let data_routes = create_collection
+        .or(drop_collection)
+        .or(list_collections)
+        .or(geoadd)
+        .or(geosearch)
+        .or(geosearchbox)
+        .or(geosearchhash)
+        .or(geowithin)
+        .or(geointersects)
+        .or(geocontains)
+        .or(geocontaining)
+        .or(geocontaining_batch)
+        .or(geonearest)
+        .or(geodist)
+        .or(geoget)
+        .or(geosetprops)
+        .or(geoexpire)
+        .or(geottl)
+        .or(geohash)
+        .or(geodel)
+        .or(create_index)
+        .or(drop_index)
+        .or(list_indexes)
+        .or(create_h3_index)
+        .or(drop_h3_index)
+        .or(geoh3cell)
+        .or(geoh3keys)
+        .or(geoh3polyfill)
+        .boxed();
+
+    data_routes
+        .or(save)
+        .or(bgsave)
+        .or(lastsave)
+        .or(info)
+        .or(health)
+}
+
+/// A 400 reply carrying `message` as its JSON body.
+fn bad_request(message: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&message), StatusCode::BAD_REQUEST)
+}
+
+/// A 404 reply carrying `message` as its JSON body.
+fn not_found(message: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&message), StatusCode::NOT_FOUND)
+}
+
+/// A 200 reply with `value` as its JSON body.
+fn ok(value: &impl Serialize) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(value), StatusCode::OK)
+}
+
+/// Reorders pairs written in `axis` order into `(lat, lon)`.
+fn to_lat_lon(coords: &[(f64, f64)], axis: AxisOrder) -> Vec<(f64, f64)> {
+    coords.iter().map(|&pair| axis.to_lat_lon(pair)).collect()
+}
+
+/// The reply to a snapshot that could not be taken: a `409` while another
+/// one is being written, a `500` otherwise.
+fn snapshot_failed(e: &io::Error) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match e.kind() {
+        io::ErrorKind::ResourceBusy => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status(warp::reply::json(&e.to_string()), status)
+}
+
+async fn handle_save(
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = tokio::task::spawn_blocking(move || persistence::save(&collections, &persistence))
+        .await
+        .unwrap();
+    match result {
+        Ok(_) => Ok(ok(&"OK")),
+        Err(e) => {
+            error!("Failed to create snapshot; err = {:?}", e);
+            Ok(snapshot_failed(&e))
+        }
+    }
+}
+
+async fn handle_bgsave(
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match persistence::background_save(&collections, &persistence) {
+        Ok(()) => Ok(ok(&"Background saving started")),
+        Err(e) => Ok(snapshot_failed(&e)),
+    }
+}
+
+async fn handle_lastsave(
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let last_save = persistence.lock().unwrap().last_save();
+    Ok(ok(&json!({ "lastsave": last_save })))
+}
+
+async fn handle_info(
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut info = {
+        let collections = collections.lock().unwrap();
+        vec![
+            (
+                "collections",
+                collections.collection_names().len().to_string(),
+            ),
+            ("keys", collections.key_count().to_string()),
+        ]
+    };
+    info.extend(persistence.lock().unwrap().info());
+    let info: Map<String, Value> = info
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), Value::String(value)))
+        .collect();
+    Ok(ok(&info))
 }
 
 async fn handle_health() -> Result<impl warp::Reply, warp::Rejection> {
     Ok(warp::reply::json(&"HEALTH"))
 }
 
-fn with_db(
-    db: Arc<Mutex<GeoDatabase>>,
-) -> impl Filter<Extract = (Arc<Mutex<GeoDatabase>>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || db.clone())
+fn with_collections(
+    collections: Arc<Mutex<GeoCollections>>,
+) -> impl Filter<Extract = (Arc<Mutex<GeoCollections>>,), Error = std::convert::Infallible> + Clone
+{
+    warp::any().map(move || collections.clone())
+}
+
+fn with_persistence(
+    persistence: Arc<Mutex<Persistence>>,
+) -> impl Filter<Extract = (Arc<Mutex<Persistence>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || persistence.clone())
+}
+
+async fn handle_create_collection(
+    collection: String,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut collections = collections.lock().unwrap();
+    if !collections.create_collection(&collection) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&"Exists"),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    let mut persistence = persistence.lock().unwrap();
+    if let Err(e) = persistence.log_entry(WalEntry::CreateCollection { collection }) {
+        error!("Failed to log entry; err = {:?}", e);
+    }
+    Ok(ok(&"OK"))
+}
+
+async fn handle_drop_collection(
+    collection: String,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut collections = collections.lock().unwrap();
+    if !collections.drop_collection(&collection) {
+        return Ok(not_found("Not Found"));
+    }
+
+    let mut persistence = persistence.lock().unwrap();
+    if let Err(e) = persistence.log_entry(WalEntry::DropCollection { collection }) {
+        error!("Failed to log entry; err = {:?}", e);
+    }
+    Ok(ok(&"OK"))
+}
+
+async fn handle_list_collections(
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collections = collections.lock().unwrap();
+    Ok(warp::reply::json(&collections.collection_names()))
 }
 
 async fn handle_geoadd(
+    collection: String,
+    query: AxisQuery,
     body: GeoAddRequest,
-    db: Arc<Mutex<GeoDatabase>>,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().unwrap();
-    db.geo_add(body.key, body.coords);
-    Ok(warp::reply::json(&"OK"))
+    let geometry = match (body.coords, body.wkt, body.geohash) {
+        (Some(coords), None, None) => geometry_from_coords(&to_lat_lon(&coords, query.axis)),
+        (None, Some(wkt), None) => geometry_from_wkt(&wkt, query.axis),
+        (None, None, Some(hash)) => geometry_from_geohash(&hash),
+        _ => {
+            return Ok(bad_request(
+                "Expected a geometry as `coords`, `wkt` or `geohash`",
+            ))
+        }
+    };
+    let geometry = match geometry {
+        Ok(geometry) => geometry,
+        Err(e) => return Ok(bad_request(&e.to_string())),
+    };
+
+    let properties = body.properties.map(properties_from_map);
+    let expires_at = match body.expire_seconds {
+        Some(0) => return Ok(bad_request(INVALID_EXPIRE_TIME)),
+        Some(seconds) => match expiry_deadline(seconds) {
+            Some(at) => Some(at),
+            None => return Ok(bad_request(INVALID_EXPIRE_TIME)),
+        },
+        None => None,
+    };
+
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let held = collections
+        .get(&collection)
+        .is_ok_and(|db| db.holds_key(&body.key));
+    let outcome = match collections.geo_add_if(
+        &collection,
+        body.key.clone(),
+        geometry.clone(),
+        body.condition,
+    ) {
+        Ok(outcome) => outcome,
+        Err(e) => return Ok(bad_request(&e.to_string())),
+    };
+
+    if outcome != AddOutcome::Skipped {
+        let db = collections.get_mut(&collection).unwrap();
+        if let Some(properties) = &properties {
+            db.geo_set_props(&body.key, properties.clone());
+        }
+        if let Some(at) = expires_at {
+            db.geo_expire_at(&body.key, at);
+        }
+        let mut persistence = persistence.lock().unwrap();
+        // A key held but created anew had expired and was deleted first,
+        // properties and all; replay has to do the same.
+        if held && outcome == AddOutcome::Created {
+            if let Err(e) = persistence.log_entry(WalEntry::GeoDel {
+                collection: collection.clone(),
+                keys: vec![body.key.clone()],
+            }) {
+                error!("Failed to log entry; err = {:?}", e);
+            }
+        }
+        if let Err(e) = persistence.log_entry(WalEntry::GeoAdd {
+            collection,
+            key: body.key,
+            geometry,
+            properties,
+            expires_at,
+        }) {
+            error!("Failed to log entry; err = {:?}", e);
+        }
+    }
+    Ok(ok(&outcome))
 }
 
 async fn handle_geosearch(
+    collection: String,
+    query: AxisQuery,
     body: GeoSearchRequest,
-    db: Arc<Mutex<GeoDatabase>>,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let filters: Vec<PropertyFilter> = body
+        .filters
+        .into_iter()
+        .map(|filter| PropertyFilter {
+            field: filter.field,
+            op: filter.op,
+            value: filter.value.into(),
+        })
+        .collect();
+    if let Err(e) = validate_lat_lon(body.lat, body.lon) {
+        return Ok(bad_request(&e.to_string()));
+    }
+
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if body.count == Some(0) {
+        return Ok(bad_request("`count` has to be at least 1"));
+    }
+    let options = SearchOptions {
+        order: body.sort,
+        count: body.count,
+        any: body.any,
+    };
+    let radius = body.unit.to_metres(body.radius);
+    let hits = db.geo_search_hits(body.lat, body.lon, radius, &filters, &options);
+    if !(body.with_dist || body.with_coord || body.with_type || body.with_props) {
+        let keys: Vec<&String> = hits.iter().map(|hit| &hit.key).collect();
+        return Ok(ok(&keys));
+    }
+
+    let results: Vec<Value> = hits
+        .iter()
+        .map(|hit| {
+            let mut result = Map::new();
+            result.insert("key".to_string(), json!(hit.key));
+            if body.with_dist {
+                result.insert(
+                    "distance".to_string(),
+                    json!(body.unit.from_metres(hit.distance)),
+                );
+            }
+            if let Some(geometry) = db.geo_geometry(&hit.key) {
+                if body.with_coord {
+                    let coordinates: Vec<(f64, f64)> = geometry_position(geometry)
+                        .into_iter()
+                        .map(|pair| query.axis.from_lat_lon(pair))
+                        .collect();
+                    result.insert("coordinates".to_string(), json!(coordinates));
+                }
+                if body.with_type {
+                    result.insert("type".to_string(), json!(geometry_type(geometry)));
+                }
+            }
+            if body.with_props {
+                let properties = db.geo_props(&hit.key).cloned().unwrap_or_default();
+                result.insert("properties".to_string(), properties_to_json(&properties));
+            }
+            Value::Object(result)
+        })
+        .collect();
+    Ok(ok(&results))
+}
+
+async fn handle_geosearchbox(
+    collection: String,
+    body: GeoSearchBoxRequest,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err(e) = validate_box(body.min_lat, body.min_lon, body.max_lat, body.max_lon) {
+        return Ok(bad_request(&e.to_string()));
+    }
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    match db.geo_search_box(
+        body.min_lat,
+        body.min_lon,
+        body.max_lat,
+        body.max_lon,
+        body.mode,
+    ) {
+        Ok(results) => Ok(ok(&results)),
+        Err(e) => Ok(bad_request(&e.to_string())),
+    }
+}
+
+async fn handle_geosearchhash(
+    collection: String,
+    prefix: String,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let cell = match geohash_cell(&prefix) {
+        Ok(cell) => cell,
+        Err(e) => return Ok(bad_request(&e.to_string())),
+    };
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    match db.geo_search_box(
+        cell.min().y,
+        cell.min().x,
+        cell.max().y,
+        cell.max().x,
+        BoxMatch::Intersects,
+    ) {
+        Ok(results) => Ok(ok(&results)),
+        Err(e) => Ok(bad_request(&e.to_string())),
+    }
+}
+
+async fn handle_geosearch_area(
+    collection: String,
+    query: AxisQuery,
+    body: GeoAreaRequest,
+    collections: Arc<Mutex<GeoCollections>>,
+    mode: AreaMatch,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let area = match (body.coords, body.wkt) {
+        (Some(coords), None) if coords.len() >= 3 => {
+            Ok(polygon_from_lat_lon(&to_lat_lon(&coords, query.axis)))
+        }
+        (None, Some(wkt)) => polygon_from_wkt(&wkt, query.axis),
+        _ => return Ok(bad_request("Expected a polygon as `coords` or `wkt`")),
+    };
+    let area = match area.and_then(|area| validate_polygon(&area).map(|()| area)) {
+        Ok(area) => area,
+        Err(e) => return Ok(bad_request(&e.to_string())),
+    };
+
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let results = db.geo_search_area(&area, mode);
+    Ok(ok(&results))
+}
+
+async fn handle_geocontaining(
+    collection: String,
+    body: GeoContainingRequest,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err(e) = validate_lat_lon(body.lat, body.lon) {
+        return Ok(bad_request(&e.to_string()));
+    }
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let results = db.geo_containing(body.lat, body.lon);
+    Ok(ok(&results))
+}
+
+async fn handle_geocontaining_batch(
+    collection: String,
+    query: AxisQuery,
+    body: GeoContainingBatchRequest,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let points: Vec<(f64, f64)> = body
+        .points
+        .iter()
+        .map(|&pair| query.axis.to_lat_lon(pair))
+        .collect();
+    if let Err(e) = points
+        .iter()
+        .try_for_each(|&(lat, lon)| validate_lat_lon(lat, lon))
+    {
+        return Ok(bad_request(&e.to_string()));
+    }
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let results: Vec<Vec<String>> = points
+        .iter()
+        .map(|&(lat, lon)| db.geo_containing(lat, lon))
+        .collect();
+    Ok(ok(&results))
+}
+
+async fn handle_geonearest(
+    collection: String,
+    body: GeoNearestRequest,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Err(e) = validate_lat_lon(body.lat, body.lon) {
+        return Ok(bad_request(&e.to_string()));
+    }
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let max_distance = body
+        .max_distance
+        .map(|distance| body.unit.to_metres(distance));
+    let hits: Vec<SearchHit> = db
+        .geo_nearest(body.lat, body.lon, body.k, max_distance)
+        .into_iter()
+        .map(|hit| SearchHit {
+            distance: body.unit.from_metres(hit.distance),
+            ..hit
+        })
+        .collect();
+    Ok(ok(&hits))
+}
+
+async fn handle_geodist(
+    collection: String,
+    body: GeoDistRequest,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    match db.geo_dist(&body.key1, &body.key2, body.method) {
+        Some(Ok(distance)) => Ok(ok(&json!({ "distance": body.unit.from_metres(distance) }))),
+        Some(Err(e)) => Ok(bad_request(&e.to_string())),
+        None => Ok(not_found("Not Found")),
+    }
+}
+
+async fn handle_geoget(
+    collection: String,
+    key: String,
+    query: AxisQuery,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let Some(wkt) = db.geo_get(&key, query.axis) else {
+        return Ok(not_found("Not Found"));
+    };
+
+    let properties = db.geo_props(&key).cloned().unwrap_or_default();
+    Ok(ok(
+        &json!({ "wkt": wkt, "properties": properties_to_json(&properties) }),
+    ))
+}
+
+async fn handle_geosetprops(
+    collection: String,
+    key: String,
+    body: Map<String, Value>,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let properties = properties_from_map(body);
+
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let db = match collections.get_mut(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if !db.geo_set_props(&key, properties.clone()) {
+        return Ok(not_found("Not Found"));
+    }
+
+    let mut persistence = persistence.lock().unwrap();
+    if let Err(e) = persistence.log_entry(WalEntry::GeoSetProps {
+        collection,
+        key,
+        properties,
+    }) {
+        error!("Failed to log entry; err = {:?}", e);
+    }
+    Ok(ok(&"OK"))
+}
+
+async fn handle_geoexpire(
+    collection: String,
+    key: String,
+    body: GeoExpireRequest,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let at = match expiry_deadline(body.seconds) {
+        Some(at) => at,
+        None => return Ok(bad_request(INVALID_EXPIRE_TIME)),
+    };
+
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let db = match collections.get_mut(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if !db.geo_expire_at(&key, at) {
+        return Ok(not_found("Not Found"));
+    }
+
+    let mut persistence = persistence.lock().unwrap();
+    if let Err(e) = persistence.log_entry(WalEntry::GeoExpire {
+        collection,
+        key,
+        at,
+    }) {
+        error!("Failed to log entry; err = {:?}", e);
+    }
+    Ok(ok(&"OK"))
+}
+
+/// Replies with the whole seconds left before `key` expires, or `null` if it
+/// never does.
+async fn handle_geottl(
+    collection: String,
+    key: String,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let ttl = match db.geo_ttl(&key) {
+        KeyTtl::Missing => return Ok(not_found("Not Found")),
+        KeyTtl::Persistent => None,
+        KeyTtl::ExpiresIn(remaining) => Some(remaining.as_millis().div_ceil(1000) as u64),
+    };
+    Ok(ok(&json!({ "ttl": ttl })))
+}
+
+async fn handle_geohash(
+    collection: String,
+    key: String,
+    query: GeoHashQuery,
+    collections: Arc<Mutex<GeoCollections>>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().unwrap();
-    let results = db.geo_search(body.lat, body.lon, body.radius);
-    Ok(warp::reply::json(&results))
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let Some(geometry) = db.geo_geometry(&key) else {
+        return Ok(not_found("Not Found"));
+    };
+    match geometry_geohash(geometry, query.precision) {
+        Ok(hash) => Ok(ok(&json!({ "geohash": hash }))),
+        Err(e) => Ok(bad_request(&e.to_string())),
+    }
 }
 
+async fn handle_create_index(
+    collection: String,
+    field: String,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let db = match collections.get_mut(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if db.create_index(&field) {
+        let mut persistence = persistence.lock().unwrap();
+        if let Err(e) = persistence.log_entry(WalEntry::CreateIndex { collection, field }) {
+            error!("Failed to log entry; err = {:?}", e);
+        }
+    }
+    Ok(ok(&"OK"))
+}
+
+async fn handle_drop_index(
+    collection: String,
+    field: String,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let db = match collections.get_mut(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if !db.drop_index(&field) {
+        return Ok(not_found("Not Found"));
+    }
+
+    let mut persistence = persistence.lock().unwrap();
+    if let Err(e) = persistence.log_entry(WalEntry::DropIndex { collection, field }) {
+        error!("Failed to log entry; err = {:?}", e);
+    }
+    Ok(ok(&"OK"))
+}
+
+async fn handle_create_h3_index(
+    collection: String,
+    resolution: u8,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let resolution = match parse_resolution(resolution) {
+        Ok(resolution) => resolution,
+        Err(message) => return Ok(bad_request(&message)),
+    };
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let db = match collections.get_mut(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if db.create_h3_index(resolution) {
+        let mut persistence = persistence.lock().unwrap();
+        if let Err(e) = persistence.log_entry(WalEntry::CreateH3Index {
+            collection,
+            resolution,
+        }) {
+            error!("Failed to log entry; err = {:?}", e);
+        }
+    }
+    Ok(ok(&"OK"))
+}
+
+async fn handle_drop_h3_index(
+    collection: String,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let db = match collections.get_mut(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if !db.drop_h3_index() {
+        return Ok(not_found("Not Found"));
+    }
+
+    let mut persistence = persistence.lock().unwrap();
+    if let Err(e) = persistence.log_entry(WalEntry::DropH3Index { collection }) {
+        error!("Failed to log entry; err = {:?}", e);
+    }
+    Ok(ok(&"OK"))
+}
+
+fn parse_resolution(resolution: u8) -> Result<Resolution, String> {
+    Resolution::try_from(resolution)
+        .map_err(|_| format!("H3 resolution {} is outside [0, 15]", resolution))
+}
+
+/// The resolution a request asked for, or else that of the H3 index.
+fn h3_resolution(db: &GeoDatabase, resolution: Option<u8>) -> Result<Resolution, String> {
+    match resolution {
+        Some(resolution) => parse_resolution(resolution),
+        None => db.h3_resolution().ok_or_else(|| NO_RESOLUTION.to_string()),
+    }
+}
+
+async fn handle_geoh3cell(
+    collection: String,
+    key: String,
+    query: H3ResolutionQuery,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let resolution = match h3_resolution(db, query.resolution) {
+        Ok(resolution) => resolution,
+        Err(message) => return Ok(bad_request(&message)),
+    };
+    match db.geo_h3_cell(&key, resolution) {
+        Some(cell) => Ok(ok(&json!({ "cell": cell.to_string() }))),
+        None => Ok(not_found("Not Found")),
+    }
+}
+
+async fn handle_geoh3keys(
+    collection: String,
+    cell: String,
+    query: H3KeysQuery,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Ok(cell) = cell.parse::<CellIndex>() else {
+        return Ok(bad_request(&format!("invalid H3 cell '{}'", cell)));
+    };
+    if query.k > MAX_GRID_DISK_K {
+        return Ok(bad_request(&format!(
+            "k {} is outside [0, {}]",
+            query.k, MAX_GRID_DISK_K
+        )));
+    }
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    match db.geo_h3_keys(cell, query.k) {
+        Some(keys) => Ok(ok(&keys)),
+        None => Ok(bad_request(NO_H3_INDEX)),
+    }
+}
+
+async fn handle_geoh3polyfill(
+    collection: String,
+    key: String,
+    query: H3ResolutionQuery,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    let resolution = match h3_resolution(db, query.resolution) {
+        Ok(resolution) => resolution,
+        Err(message) => return Ok(bad_request(&message)),
+    };
+    match db.geo_h3_polyfill(&key, resolution) {
+        Some(Ok(cells)) => Ok(ok(&cells
+            .iter()
+            .map(|cell| cell.to_string())
+            .collect::<Vec<_>>())),
+        Some(Err(e)) => Ok(bad_request(&e.to_string())),
+        None => Ok(not_found("Not Found")),
+    }
+}
+
+async fn handle_list_indexes(
+    collection: String,
+    collections: Arc<Mutex<GeoCollections>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let collections = collections.lock().unwrap();
+    let db = match collections.get(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    Ok(ok(&db.indexed_fields()))
+}
+
+async fn handle_geodel(
+    collection: String,
+    key: String,
+    collections: Arc<Mutex<GeoCollections>>,
+    persistence: Arc<Mutex<Persistence>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    unshare(&collections, &collection);
+    let mut collections = collections.lock().unwrap();
+    let db = match collections.get_mut(&collection) {
+        Ok(db) => db,
+        Err(e) => return Ok(not_found(&e.to_string())),
+    };
+    if !db.geo_del(&key) {
+        return Ok(not_found("Not Found"));
+    }
+
+    let mut persistence = persistence.lock().unwrap();
+    if let Err(e) = persistence.log_entry(WalEntry::GeoDel {
+        collection,
+        keys: vec![key],
+    }) {
+        error!("Failed to log entry; err = {:?}", e);
+    }
+    Ok(ok(&"OK"))
+}
@@ -0,0 +1,138 @@
+use crate::storage::{AddCondition, AddOutcome, GeoDatabase, GeometryError};
+use geo::Geometry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// The named collections on a server. Each one is a separate `GeoDatabase`
+/// with its own keys, R-trees, secondary indexes and expiries, so the same key
+/// can exist in two collections and a search only ever sees one of them.
+///
+/// Collections are shared copy-on-write, so a clone is cheap: it is a
+/// point-in-time copy for a background snapshot, and a collection written to
+/// afterwards is copied once, on its first write. Writers call `unshare` first
+/// so that copy is made without holding the lock.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GeoCollections {
+    collections: HashMap<String, Arc<GeoDatabase>>,
+}
+
+/// A command named a collection that does not exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownCollection(pub String);
+
+impl fmt::Display for UnknownCollection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no such collection '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCollection {}
+
+impl GeoCollections {
+    pub fn new() -> Self {
+        GeoCollections::default()
+    }
+
+    /// Creates an empty collection. Returns `false` if it already exists.
+    pub fn create_collection(&mut self, name: &str) -> bool {
+        if self.collections.contains_key(name) {
+            return false;
+        }
+        self.collections
+            .insert(name.to_string(), Arc::new(GeoDatabase::new()));
+        true
+    }
+
+    /// Drops a collection along with every key and index in it. Returns
+    /// `false` if there was no such collection.
+    pub fn drop_collection(&mut self, name: &str) -> bool {
+        self.collections.remove(name).is_some()
+    }
+
+    /// The collection names, sorted.
+    pub fn collection_names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.collections.keys().collect();
+        names.sort();
+        names
+    }
+
+    /// How many keys are stored across every collection.
+    pub fn key_count(&self) -> usize {
+        self.collections.values().map(|db| db.key_count()).sum()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&GeoDatabase, UnknownCollection> {
+        self.collections
+            .get(name)
+            .map(Arc::as_ref)
+            .ok_or_else(|| UnknownCollection(name.to_string()))
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Result<&mut GeoDatabase, UnknownCollection> {
+        self.collections
+            .get_mut(name)
+            .map(Arc::make_mut)
+            .ok_or_else(|| UnknownCollection(name.to_string()))
+    }
+
+    /// Adds `key` to `collection` like `GeoDatabase::geo_add_if`, creating the
+    /// collection first if it does not exist. A collection created here is
+    /// dropped again when nothing ends up stored in it.
+    pub fn geo_add_if(
+        &mut self,
+        collection: &str,
+        key: String,
+        geometry: Geometry<f64>,
+        condition: Option<AddCondition>,
+    ) -> Result<AddOutcome, GeometryError> {
+        let created = self.create_collection(collection);
+        let result = self
+            .get_mut(collection)
+            .unwrap()
+            .geo_add_if(key, geometry, condition);
+        if created && !matches!(result, Ok(AddOutcome::Created)) {
+            self.collections.remove(collection);
+        }
+        result
+    }
+
+    /// The collections `purge_expired` would remove anything from at `now`.
+    pub fn expiring_collections(&self, now: u64) -> Vec<String> {
+        self.collections
+            .iter()
+            .filter(|(_, db)| db.has_expired_keys(now))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Runs `GeoDatabase::purge_expired` on every collection, returning the
+    /// removed keys of each collection that lost any.
+    pub fn purge_expired(&mut self, now: u64) -> Vec<(String, Vec<String>)> {
+        self.collections
+            .iter_mut()
+            .filter(|(_, db)| db.has_expired_keys(now))
+            .map(|(name, db)| (name.clone(), Arc::make_mut(db).purge_expired(now)))
+            .collect()
+    }
+}
+
+/// Copies collection `name` away from a snapshot still sharing it, ahead of a
+/// write. The copy is made without holding the lock, so a collection of
+/// millions of keys does not stall every other client while a background
+/// snapshot is being written; it is only put in place if nothing replaced the
+/// collection meanwhile. Otherwise the write copies it under the lock as usual.
+pub fn unshare(collections: &Mutex<GeoCollections>, name: &str) {
+    let shared = match collections.lock().unwrap().collections.get(name) {
+        Some(db) if Arc::strong_count(db) > 1 => Arc::clone(db),
+        _ => return,
+    };
+    let copy = Arc::new(GeoDatabase::clone(&shared));
+    let mut collections = collections.lock().unwrap();
+    if let Some(db) = collections.collections.get_mut(name) {
+        if Arc::ptr_eq(db, &shared) {
+            *db = copy;
+        }
+    }
+}
@@ -1 +1,426 @@
+use crate::storage::GeometryError;
+use geo::vincenty_distance::FailedToConvergeError;
+use geo::{
+    coord, BoundingRect, Centroid, Closest, CoordsIter, GeodesicDistance, Geometry,
+    HaversineClosestPoint, HaversineDistance, Intersects, MapCoords, Point, Polygon, Rect,
+    VincentyDistance,
+};
+use geohash::GeohashError;
+use rstar::AABB;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use wkt::{ToWkt, Wkt};
 
+/// The order clients write coordinate pairs in.
+///
+/// Internally every geometry is stored with x = longitude and y = latitude,
+/// whatever order it arrived in. Commands take `lat lon` pairs by default;
+/// GeoJSON-style clients can switch a connection (or a REST request) to
+/// `LONLAT` and send `lon lat` instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum AxisOrder {
+    #[default]
+    LatLon,
+    LonLat,
+}
+
+impl AxisOrder {
+    /// Reorders a pair written in this order into `(lat, lon)`.
+    pub fn to_lat_lon(self, (first, second): (f64, f64)) -> (f64, f64) {
+        match self {
+            AxisOrder::LatLon => (first, second),
+            AxisOrder::LonLat => (second, first),
+        }
+    }
+
+    /// Writes a `(lat, lon)` pair in this order.
+    pub fn from_lat_lon(self, (lat, lon): (f64, f64)) -> (f64, f64) {
+        match self {
+            AxisOrder::LatLon => (lat, lon),
+            AxisOrder::LonLat => (lon, lat),
+        }
+    }
+}
+
+/// The unit a command's distances are written and reported in. Distances are
+/// always metres internally.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DistanceUnit {
+    #[default]
+    #[serde(rename = "m")]
+    Metres,
+    #[serde(rename = "km")]
+    Kilometres,
+    #[serde(rename = "mi")]
+    Miles,
+    #[serde(rename = "ft")]
+    Feet,
+    #[serde(rename = "nmi")]
+    NauticalMiles,
+}
+
+impl DistanceUnit {
+    fn metres(self) -> f64 {
+        match self {
+            DistanceUnit::Metres => 1.0,
+            DistanceUnit::Kilometres => 1000.0,
+            DistanceUnit::Miles => 1609.344,
+            DistanceUnit::Feet => 0.3048,
+            DistanceUnit::NauticalMiles => 1852.0,
+        }
+    }
+
+    /// Converts a distance in this unit to metres.
+    pub fn to_metres(self, distance: f64) -> f64 {
+        distance * self.metres()
+    }
+
+    /// Converts a distance in metres to this unit.
+    pub fn from_metres(self, distance: f64) -> f64 {
+        distance / self.metres()
+    }
+}
+
+impl FromStr for DistanceUnit {
+    type Err = ();
+
+    fn from_str(unit: &str) -> Result<Self, Self::Err> {
+        match unit.to_ascii_lowercase().as_str() {
+            "m" => Ok(DistanceUnit::Metres),
+            "km" => Ok(DistanceUnit::Kilometres),
+            "mi" => Ok(DistanceUnit::Miles),
+            "ft" => Ok(DistanceUnit::Feet),
+            "nmi" => Ok(DistanceUnit::NauticalMiles),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How `geometry_distance` measures between two points.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceMethod {
+    /// Great-circle distance on a sphere. Fast, but up to about 0.5% off.
+    #[default]
+    Haversine,
+    /// Vincenty's formulae on the WGS84 ellipsoid. Sub-millimetre, but does
+    /// not converge for nearly antipodal points.
+    Vincenty,
+    /// Karney's geodesic on the WGS84 ellipsoid. Sub-millimetre everywhere.
+    Geodesic,
+}
+
+impl DistanceMethod {
+    /// The distance in metres between two points.
+    pub fn measure(self, from: &Point<f64>, to: &Point<f64>) -> Result<f64, FailedToConvergeError> {
+        match self {
+            DistanceMethod::Haversine => Ok(from.haversine_distance(to)),
+            DistanceMethod::Vincenty => from.vincenty_distance(to),
+            DistanceMethod::Geodesic => Ok(from.geodesic_distance(to)),
+        }
+    }
+}
+
+impl FromStr for DistanceMethod {
+    type Err = ();
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method.to_ascii_lowercase().as_str() {
+            "haversine" => Ok(DistanceMethod::Haversine),
+            "vincenty" => Ok(DistanceMethod::Vincenty),
+            "geodesic" => Ok(DistanceMethod::Geodesic),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Mean earth radius in metres, the same value `geo` uses for its haversine measures.
+pub const MEAN_EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Returns the (x=lon, y=lat) envelopes, in degrees, that cover every location
+/// within `radius` metres of `center`.
+///
+/// The longitude half-width grows with latitude, and a cap that reaches a pole
+/// spans every longitude. A cap that crosses the antimeridian is split in two
+/// so the caller can query an R-tree with each half.
+pub fn radius_envelopes(center: Point<f64>, radius: f64) -> Vec<AABB<Point<f64>>> {
+    let angular_radius = radius / MEAN_EARTH_RADIUS;
+    let lat = center.y().to_radians();
+
+    let min_lat = (lat - angular_radius).to_degrees();
+    let max_lat = (lat + angular_radius).to_degrees();
+    if min_lat <= -90.0 || max_lat >= 90.0 || angular_radius.sin() >= lat.cos() {
+        return vec![AABB::from_corners(
+            Point::new(-180.0, min_lat.max(-90.0)),
+            Point::new(180.0, max_lat.min(90.0)),
+        )];
+    }
+
+    let delta_lon = (angular_radius.sin() / lat.cos()).asin().to_degrees();
+    lon_span_envelopes(
+        center.x() - delta_lon,
+        center.x() + delta_lon,
+        min_lat,
+        max_lat,
+    )
+}
+
+/// Returns the envelopes for a lat/lon box. A box whose `min_lon` is greater
+/// than its `max_lon` crosses the antimeridian and is split in two.
+pub fn box_envelopes(
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+) -> Vec<AABB<Point<f64>>> {
+    if min_lon > max_lon {
+        lon_span_envelopes(min_lon, max_lon + 360.0, min_lat, max_lat)
+    } else {
+        lon_span_envelopes(min_lon, max_lon, min_lat, max_lat)
+    }
+}
+
+/// Builds the envelopes for a longitude span that may run past ±180°, wrapping
+/// the overflowing part around to the other side of the antimeridian.
+pub fn lon_span_envelopes(
+    min_lon: f64,
+    max_lon: f64,
+    min_lat: f64,
+    max_lat: f64,
+) -> Vec<AABB<Point<f64>>> {
+    let envelope = |min_lon: f64, max_lon: f64| {
+        AABB::from_corners(Point::new(min_lon, min_lat), Point::new(max_lon, max_lat))
+    };
+
+    if max_lon - min_lon >= 360.0 {
+        vec![envelope(-180.0, 180.0)]
+    } else if min_lon < -180.0 {
+        vec![envelope(-180.0, max_lon), envelope(min_lon + 360.0, 180.0)]
+    } else if max_lon > 180.0 {
+        vec![envelope(min_lon, 180.0), envelope(-180.0, max_lon - 360.0)]
+    } else {
+        vec![envelope(min_lon, max_lon)]
+    }
+}
+
+/// Haversine distance in metres from `center` to the nearest part of
+/// `geometry`. Zero means `center` lies inside or on the geometry.
+pub fn haversine_distance_to<G: HaversineClosestPoint<f64>>(
+    center: &Point<f64>,
+    geometry: &G,
+) -> f64 {
+    match geometry.haversine_closest_point(center) {
+        Closest::Intersection(_) => 0.0,
+        Closest::SinglePoint(closest) => closest.haversine_distance(center),
+        // Degenerate geometries (e.g. a polygon with fewer than three vertices)
+        // have no meaningful distance and never match a query.
+        Closest::Indeterminate => f64::INFINITY,
+    }
+}
+
+/// The distance in metres between the closest parts of `a` and `b`, zero if
+/// they intersect. The closest pair of points is found by haversine distance
+/// between the vertices of each geometry and the other geometry, and then
+/// measured with `method`.
+pub fn geometry_distance(
+    a: &Geometry<f64>,
+    b: &Geometry<f64>,
+    method: DistanceMethod,
+) -> Result<f64, FailedToConvergeError> {
+    if a.intersects(b) {
+        return Ok(0.0);
+    }
+    let closest = [nearest_vertex_pair(a, b), nearest_vertex_pair(b, a)]
+        .into_iter()
+        .flatten()
+        .min_by(|x, y| x.2.total_cmp(&y.2));
+    match closest {
+        Some((from, to, _)) => method.measure(&from, &to),
+        None => Ok(f64::INFINITY),
+    }
+}
+
+/// The vertex of `from` closest to `to`, the point on `to` it is closest to,
+/// and their haversine distance.
+fn nearest_vertex_pair(
+    from: &Geometry<f64>,
+    to: &Geometry<f64>,
+) -> Option<(Point<f64>, Point<f64>, f64)> {
+    from.coords_iter()
+        .filter_map(|coord| {
+            let vertex = Point::from(coord);
+            match to.haversine_closest_point(&vertex) {
+                Closest::SinglePoint(closest) | Closest::Intersection(closest) => {
+                    Some((vertex, closest, vertex.haversine_distance(&closest)))
+                }
+                Closest::Indeterminate => None,
+            }
+        })
+        .min_by(|x, y| x.2.total_cmp(&y.2))
+}
+
+/// Squared distance, in the degree plane the R-trees are built on, from
+/// `center` to the farthest corner of `radius_envelopes(center, radius)`.
+///
+/// Anything within `radius` metres of `center` lies inside those envelopes, so
+/// an R-tree entry further than this from `center` cannot be within `radius`.
+/// This lets a nearest-neighbour walk in degree order stop once it is past the
+/// geodesic distance it still has to beat.
+pub fn envelope_reach_2(center: Point<f64>, radius: f64) -> f64 {
+    if !radius.is_finite() {
+        return f64::INFINITY;
+    }
+
+    radius_envelopes(center, radius)
+        .iter()
+        .flat_map(|envelope| {
+            let (lower, upper) = (envelope.lower(), envelope.upper());
+            [
+                lower,
+                upper,
+                Point::new(lower.x(), upper.y()),
+                Point::new(upper.x(), lower.y()),
+            ]
+        })
+        .map(|corner| (corner.x() - center.x()).powi(2) + (corner.y() - center.y()).powi(2))
+        .fold(0.0, f64::max)
+}
+
+/// Builds a polygon from a ring of (lat, lon) pairs, the order commands take
+/// coordinates in.
+pub fn polygon_from_lat_lon(coords: &[(f64, f64)]) -> Polygon<f64> {
+    Polygon::new(
+        coords
+            .iter()
+            .map(|&(lat, lon)| coord! { x: lon, y: lat })
+            .collect(),
+        vec![],
+    )
+}
+
+/// Parses a WKT `POLYGON` whose coordinates are written in `axis` order.
+pub fn polygon_from_wkt(wkt: &str, axis: AxisOrder) -> Result<Polygon<f64>, GeometryError> {
+    match geometry_from_wkt(wkt, axis)? {
+        Geometry::Polygon(polygon) => Ok(polygon),
+        _ => Err(GeometryError::UnsupportedType),
+    }
+}
+
+/// Parses a WKT geometry whose coordinates are written in `axis` order. Only
+/// the types `GeoDatabase` stores are accepted: `POINT`, `LINESTRING`,
+/// `POLYGON` (holes included), `MULTIPOINT`, `MULTILINESTRING` and
+/// `MULTIPOLYGON`. Polygon rings have to be closed.
+pub fn geometry_from_wkt(wkt: &str, axis: AxisOrder) -> Result<Geometry<f64>, GeometryError> {
+    let wkt = Wkt::<f64>::from_str(wkt).map_err(|_| GeometryError::InvalidWkt)?;
+    // `geo` closes rings when it builds a polygon, so this is the last place
+    // an unclosed ring can be told apart.
+    let rings: Vec<&wkt::types::LineString<f64>> = match &wkt {
+        Wkt::Polygon(polygon) => polygon.0.iter().collect(),
+        Wkt::MultiPolygon(polygons) => polygons.0.iter().flat_map(|p| &p.0).collect(),
+        _ => vec![],
+    };
+    let closed = |ring: &wkt::types::LineString<f64>| match (ring.0.first(), ring.0.last()) {
+        (Some(first), Some(last)) => (first.x, first.y) == (last.x, last.y),
+        _ => true,
+    };
+    if !rings.into_iter().all(closed) {
+        return Err(GeometryError::UnclosedRing);
+    }
+
+    match Geometry::try_from(wkt).map_err(|_| GeometryError::InvalidWkt)? {
+        geometry @ (Geometry::Point(_)
+        | Geometry::LineString(_)
+        | Geometry::Polygon(_)
+        | Geometry::MultiPoint(_)
+        | Geometry::MultiLineString(_)
+        | Geometry::MultiPolygon(_)) => Ok(with_axis_order(geometry, axis)),
+        _ => Err(GeometryError::UnsupportedType),
+    }
+}
+
+/// Formats `geometry` as WKT with coordinates written in `axis` order.
+pub fn geometry_to_wkt(geometry: &Geometry<f64>, axis: AxisOrder) -> String {
+    with_axis_order(geometry.clone(), axis).wkt_string()
+}
+
+/// The WKT name of a geometry's type, e.g. `POLYGON`.
+pub fn geometry_type(geometry: &Geometry<f64>) -> &'static str {
+    match geometry {
+        Geometry::Point(_) => "POINT",
+        Geometry::Line(_) | Geometry::LineString(_) => "LINESTRING",
+        Geometry::Polygon(_) | Geometry::Rect(_) | Geometry::Triangle(_) => "POLYGON",
+        Geometry::MultiPoint(_) => "MULTIPOINT",
+        Geometry::MultiLineString(_) => "MULTILINESTRING",
+        Geometry::MultiPolygon(_) => "MULTIPOLYGON",
+        Geometry::GeometryCollection(_) => "GEOMETRYCOLLECTION",
+    }
+}
+
+/// Where a geometry lies, as `(lat, lon)` pairs: a point's own coordinates,
+/// or the minimum and maximum corners of anything else's bounding box.
+pub fn geometry_position(geometry: &Geometry<f64>) -> Vec<(f64, f64)> {
+    match geometry {
+        Geometry::Point(point) => vec![(point.y(), point.x())],
+        _ => geometry.bounding_rect().map_or_else(Vec::new, |bounds| {
+            vec![
+                (bounds.min().y, bounds.min().x),
+                (bounds.max().y, bounds.max().x),
+            ]
+        }),
+    }
+}
+
+/// Builds a geometry from the untyped `GEOADD key lat lon ...` shorthand: a
+/// single pair is a point, anything longer is the closed exterior ring of a
+/// polygon.
+pub fn geometry_from_coords(coords: &[(f64, f64)]) -> Result<Geometry<f64>, GeometryError> {
+    match coords {
+        [] => Err(GeometryError::Empty),
+        [(lat, lon)] => Ok(Geometry::Point(Point::new(*lon, *lat))),
+        [first, .., last] if first != last => Err(GeometryError::UnclosedRing),
+        _ => Ok(Geometry::Polygon(polygon_from_lat_lon(coords))),
+    }
+}
+
+/// The geohash length `GEOHASH` uses when none is given, as in Redis: cells
+/// about 15 cm across.
+pub const DEFAULT_GEOHASH_PRECISION: usize = 11;
+
+/// The cell a geohash names, as a box in x = lon, y = lat. Case is ignored.
+pub fn geohash_cell(hash: &str) -> Result<Rect<f64>, GeometryError> {
+    if hash.is_empty() {
+        return Err(GeometryError::InvalidGeohash(hash.to_string()));
+    }
+    geohash::decode_bbox(&hash.to_ascii_lowercase())
+        .map_err(|_| GeometryError::InvalidGeohash(hash.to_string()))
+}
+
+/// Builds the point at the centre of a geohash cell, for `GEOADD key hash`.
+pub fn geometry_from_geohash(hash: &str) -> Result<Geometry<f64>, GeometryError> {
+    Ok(Geometry::Point(geohash_cell(hash)?.center().into()))
+}
+
+/// The geohash, `precision` characters long, of a point or of the centroid of
+/// any other geometry. Fails only for a precision outside 1..=12.
+pub fn geometry_geohash(
+    geometry: &Geometry<f64>,
+    precision: usize,
+) -> Result<String, GeohashError> {
+    let centre = match geometry {
+        Geometry::Point(point) => *point,
+        _ => geometry
+            .centroid()
+            .expect("stored geometries always have coordinates"),
+    };
+    geohash::encode(centre.into(), precision)
+}
+
+/// Converts between the stored x = lon, y = lat layout and `axis` order. The
+/// swap is its own inverse, so this works in both directions.
+fn with_axis_order(geometry: Geometry<f64>, axis: AxisOrder) -> Geometry<f64> {
+    match axis {
+        AxisOrder::LatLon => geometry.map_coords(|c| coord! { x: c.y, y: c.x }),
+        AxisOrder::LonLat => geometry,
+    }
+}
@@ -0,0 +1,142 @@
+use geo::{Centroid, Geometry, Point};
+use h3o::error::InvalidGeometry;
+use h3o::geom::{ContainmentMode, TilerBuilder};
+use h3o::{CellIndex, LatLng, Resolution};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// The most cells `polyfill` will produce, going by the tiler's estimate.
+pub const MAX_POLYFILL_CELLS: usize = 100_000;
+
+/// The largest `k` a k-ring lookup takes: 3k(k + 1) + 1 cells, about 30,000.
+pub const MAX_GRID_DISK_K: u32 = 100;
+
+/// The reply to a k-ring lookup on a collection without an H3 index.
+pub const NO_H3_INDEX: &str = "collection has no H3 index, see GEOH3INDEX";
+
+/// The reply to an H3 command that leaves out the resolution on a collection
+/// without an H3 index to take it from.
+pub const NO_RESOLUTION: &str = "no resolution given and collection has no H3 index";
+
+/// Why a geometry could not be polyfilled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolyfillError {
+    /// Only polygons and multipolygons have an area to fill.
+    NotAPolygon,
+    /// The estimated number of cells is over `MAX_POLYFILL_CELLS`.
+    TooManyCells(usize),
+    Invalid(InvalidGeometry),
+}
+
+impl fmt::Display for PolyfillError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolyfillError::NotAPolygon => write!(f, "only polygons can be polyfilled"),
+            PolyfillError::TooManyCells(estimate) => write!(
+                f,
+                "polyfill would produce about {} cells, more than the limit of {}; use a coarser resolution",
+                estimate, MAX_POLYFILL_CELLS
+            ),
+            PolyfillError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PolyfillError {}
+
+/// The H3 cell at `resolution` holding a point, or the centroid of any other
+/// geometry, the same spot `geometry_geohash` hashes.
+pub fn geometry_cell(geometry: &Geometry<f64>, resolution: Resolution) -> CellIndex {
+    let centre: Point<f64> = match geometry {
+        Geometry::Point(point) => *point,
+        _ => geometry
+            .centroid()
+            .expect("stored geometries always have coordinates"),
+    };
+    LatLng::new(centre.y(), centre.x())
+        .expect("stored coordinates are in range")
+        .to_cell(resolution)
+}
+
+/// The cells at `resolution` whose centres lie inside a polygon or
+/// multipolygon, the classic H3 polyfill.
+pub fn polyfill(
+    geometry: &Geometry<f64>,
+    resolution: Resolution,
+) -> Result<Vec<CellIndex>, PolyfillError> {
+    let polygons = match geometry {
+        Geometry::Polygon(polygon) => vec![polygon.clone()],
+        Geometry::MultiPolygon(polygons) => polygons.0.clone(),
+        _ => return Err(PolyfillError::NotAPolygon),
+    };
+    let mut tiler = TilerBuilder::new(resolution)
+        .containment_mode(ContainmentMode::ContainsCentroid)
+        .build();
+    for polygon in polygons {
+        tiler.add(polygon).map_err(PolyfillError::Invalid)?;
+    }
+    let estimate = tiler.coverage_size_hint();
+    if estimate > MAX_POLYFILL_CELLS {
+        return Err(PolyfillError::TooManyCells(estimate));
+    }
+    let cells: BTreeSet<CellIndex> = tiler.into_coverage().collect();
+    Ok(cells.into_iter().collect())
+}
+
+/// An index of keys by their `geometry_cell` at one resolution. It sits next
+/// to the R-trees and answers "what is in this cell" without any geometry.
+///
+/// `CellIndex` orders cells by their digits, so the descendants at the index
+/// resolution of any coarser cell form one contiguous range of `entries`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct H3Index {
+    resolution: Resolution,
+    entries: BTreeMap<CellIndex, BTreeSet<String>>,
+}
+
+impl H3Index {
+    pub fn new(resolution: Resolution) -> Self {
+        H3Index {
+            resolution,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn insert(&mut self, key: &str, geometry: &Geometry<f64>) {
+        self.entries
+            .entry(geometry_cell(geometry, self.resolution))
+            .or_default()
+            .insert(key.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str, geometry: &Geometry<f64>) {
+        let cell = geometry_cell(geometry, self.resolution);
+        if let Some(keys) = self.entries.get_mut(&cell) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.entries.remove(&cell);
+            }
+        }
+    }
+
+    /// The keys in `cell` or, for a cell finer than the index resolution, in
+    /// its parent at that resolution, which the caller has to narrow down.
+    pub fn lookup(&self, cell: CellIndex) -> impl Iterator<Item = &String> {
+        let (first, last) = match cell.parent(self.resolution) {
+            Some(parent) => (parent, parent),
+            None => {
+                let last = cell.children_count(self.resolution) - 1;
+                (
+                    cell.child_at(0, self.resolution).unwrap(),
+                    cell.child_at(last, self.resolution).unwrap(),
+                )
+            }
+        };
+        self.entries.range(first..=last).flat_map(|(_, keys)| keys)
+    }
+}
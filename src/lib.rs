@@ -1,5 +1,8 @@
 pub mod api;
+pub mod collections;
 pub mod geospatial;
+pub mod h3;
 pub mod network;
 pub mod persistence;
+pub mod properties;
 pub mod storage;
@@ -1,14 +1,7 @@
-mod api;
-mod network;
-mod persistence;
-mod storage;
-
-use crate::network::replica::Replica;
-use dotenv;
-use network::replica::Role;
-use network::server::start_server;
+use geommdb::network::replica::Role;
+use geommdb::network::server::start_server;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
 use std::{env, process};
 
 extern crate pretty_env_logger;
@@ -57,19 +50,25 @@ fn handle_environment() {
 
 async fn run_server(leader_addr: SocketAddr, this_addr: SocketAddr) {
     let role = env::var("ROLE").unwrap_or_else(|_| "leader".to_string());
-    match role.as_str() {
+    let data_dir = PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| ".".to_string()));
+    let result = match role.as_str() {
         "leader" => {
             info!("I am LEADER running at {}", leader_addr);
-            start_server(this_addr, None, Role::Leader).await;
+            start_server(this_addr, None, Role::Leader, data_dir).await
         }
         "replica" => {
             info!("I am replica running at: {}", this_addr);
             info!("Leader is running at {}", leader_addr);
-            start_server(this_addr, Some(leader_addr), Role::Replica).await;
+            start_server(this_addr, Some(leader_addr), Role::Replica, data_dir).await
         }
         _ => {
             eprintln!("Invalid role specified. Use 'leader' or 'replica'.");
+            return;
         }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
     }
 }
 
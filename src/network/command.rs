@@ -1,47 +1,668 @@
+use crate::geospatial::{
+    geohash_cell, geometry_from_coords, geometry_from_geohash, geometry_from_wkt,
+    polygon_from_lat_lon, polygon_from_wkt, AxisOrder, DistanceMethod, DistanceUnit,
+    DEFAULT_GEOHASH_PRECISION,
+};
+use crate::h3::MAX_GRID_DISK_K;
+use crate::properties::{parse_properties, CompareOp, Filter, PropValue, Properties};
+use crate::storage::{
+    validate_box, validate_lat_lon, validate_polygon, AddCondition, AreaMatch, BoxMatch,
+    GeometryError, SearchOptions, SortOrder, INVALID_EXPIRE_TIME,
+};
+use geo::{Geometry, Polygon};
+use h3o::{CellIndex, Resolution};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq)]
 pub enum Command {
+    CreateCollection {
+        collection: String,
+    },
+    DropCollection {
+        collection: String,
+    },
+    ListCollections,
     GeoAdd {
+        collection: String,
+        key: String,
+        geometry: Geometry<f64>,
+        properties: Option<Properties>,
+        condition: Option<AddCondition>,
+        expire_seconds: Option<u64>,
+    },
+    GeoSetProps {
+        collection: String,
         key: String,
-        coords: Vec<(f64, f64)>,
+        properties: Properties,
     },
     GeoSearch {
+        collection: String,
         lat: f64,
         lon: f64,
+        /// In metres, whatever unit the command used.
         radius: f64,
+        /// The unit to report distances in.
+        unit: DistanceUnit,
+        fields: HitFields,
+        options: SearchOptions,
+        filters: Vec<Filter>,
+    },
+    GeoSearchBox {
+        collection: String,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+        mode: BoxMatch,
+    },
+    GeoSearchArea {
+        collection: String,
+        area: Polygon<f64>,
+        mode: AreaMatch,
+    },
+    GeoContaining {
+        collection: String,
+        lat: f64,
+        lon: f64,
+    },
+    GeoContainingBatch {
+        collection: String,
+        points: Vec<(f64, f64)>,
+    },
+    GeoNearest {
+        collection: String,
+        lat: f64,
+        lon: f64,
+        k: usize,
+        /// In metres, whatever unit the command used.
+        max_distance: Option<f64>,
+        /// The unit to report distances in.
+        unit: DistanceUnit,
+    },
+    GeoDist {
+        collection: String,
+        key1: String,
+        key2: String,
+        /// The unit to report the distance in.
+        unit: DistanceUnit,
+        method: DistanceMethod,
     },
     GeoGet {
+        collection: String,
+        key: String,
+    },
+    GeoHash {
+        collection: String,
         key: String,
+        precision: usize,
+    },
+    CreateH3Index {
+        collection: String,
+        resolution: Resolution,
+    },
+    DropH3Index {
+        collection: String,
+    },
+    /// Without a resolution, the H3 commands use that of the collection's H3
+    /// index.
+    GeoH3Cell {
+        collection: String,
+        key: String,
+        resolution: Option<Resolution>,
+    },
+    GeoH3Keys {
+        collection: String,
+        cell: CellIndex,
+        k: u32,
+    },
+    GeoH3Polyfill {
+        collection: String,
+        key: String,
+        resolution: Option<Resolution>,
+    },
+    GeoDel {
+        collection: String,
+        keys: Vec<String>,
+    },
+    GeoExpire {
+        collection: String,
+        key: String,
+        seconds: u64,
+    },
+    GeoTtl {
+        collection: String,
+        key: String,
+    },
+    CreateIndex {
+        collection: String,
+        field: String,
+    },
+    DropIndex {
+        collection: String,
+        field: String,
+    },
+    ListIndexes {
+        collection: String,
+    },
+    Axis {
+        order: AxisOrder,
     },
+    Save,
+    BgSave,
+    LastSave,
+    Info,
     Heartbeat,
 }
 
-pub fn parse_command(input: &str) -> Option<Command> {
-    let parts: Vec<&str> = input.trim().split_whitespace().collect();
-    match parts.as_slice() {
-        ["GEOADD", key, rest @ ..] => {
-            let coords: Vec<(f64, f64)> = rest
-                .chunks(2)
-                .filter_map(|chunk| {
-                    if chunk.len() == 2 {
-                        Some((chunk[0].parse().ok()?, chunk[1].parse().ok()?))
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-            Some(Command::GeoAdd {
+impl Command {
+    /// The existing collection this command writes to, if any.
+    pub fn written_collection(&self) -> Option<&str> {
+        match self {
+            Command::GeoAdd { collection, .. }
+            | Command::GeoSetProps { collection, .. }
+            | Command::CreateH3Index { collection, .. }
+            | Command::DropH3Index { collection }
+            | Command::GeoDel { collection, .. }
+            | Command::GeoExpire { collection, .. }
+            | Command::CreateIndex { collection, .. }
+            | Command::DropIndex { collection, .. } => Some(collection),
+            _ => None,
+        }
+    }
+}
+
+/// What a `GEOSEARCH` reply line carries after each key, as asked for with
+/// `WITHDIST`, `WITHCOORD`, `WITHTYPE` and `WITHPROPS`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HitFields {
+    pub distance: bool,
+    pub coord: bool,
+    pub geometry_type: bool,
+    pub properties: bool,
+}
+
+/// Why a command line was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    /// Not a known command, or the wrong arguments for one.
+    Syntax,
+    /// A token that should be a number is not one.
+    InvalidNumber(String),
+    /// A coordinate list with a latitude or longitude missing its partner.
+    UnpairedCoordinate,
+    /// A property payload that is not a JSON object.
+    InvalidProperties,
+    /// A number outside the range an argument allows, e.g. a geohash
+    /// precision over 12.
+    OutOfRange {
+        name: &'static str,
+        value: u64,
+        min: u64,
+        max: u64,
+    },
+    /// A token that is not an H3 cell index in hex.
+    InvalidCell(String),
+    /// An `EX 0`, which would expire the key before it is ever read.
+    InvalidExpireTime,
+    /// A geometry that parses but cannot be stored or queried with.
+    Geometry(GeometryError),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Syntax => write!(f, "syntax error"),
+            CommandError::InvalidNumber(token) => write!(f, "invalid number '{}'", token),
+            CommandError::UnpairedCoordinate => {
+                write!(f, "coordinates must come in latitude/longitude pairs")
+            }
+            CommandError::InvalidProperties => write!(f, "properties must be a JSON object"),
+            CommandError::OutOfRange {
+                name,
+                value,
+                min,
+                max,
+            } => write!(f, "{} {} is outside [{}, {}]", name, value, min, max),
+            CommandError::InvalidCell(token) => write!(f, "invalid H3 cell '{}'", token),
+            CommandError::InvalidExpireTime => write!(f, "{}", INVALID_EXPIRE_TIME),
+            CommandError::Geometry(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<GeometryError> for CommandError {
+    fn from(e: GeometryError) -> Self {
+        CommandError::Geometry(e)
+    }
+}
+
+/// Parses one command line. Coordinate pairs are read in `axis` order and
+/// always come out as (lat, lon).
+///
+/// Every command that touches data names its collection first, e.g.
+/// `GEOADD vehicles v1 ...`.
+///
+/// A JSON property map, when a command takes one, runs from the first `{` to
+/// the end of the line and may contain spaces.
+pub fn parse_command(input: &str, axis: AxisOrder) -> Result<Command, CommandError> {
+    let (input, json) = match input.find('{') {
+        Some(start) => (&input[..start], Some(input[start..].trim())),
+        None => (input, None),
+    };
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    match (parts.as_slice(), json) {
+        (["GEOADD", collection, key, rest @ ..], json) => {
+            let (condition, rest) = match rest {
+                ["NX", rest @ ..] => (Some(AddCondition::IfNotExists), rest),
+                ["XX", rest @ ..] => (Some(AddCondition::IfExists), rest),
+                _ => (None, rest),
+            };
+            let (expire_seconds, rest) = match rest {
+                ["EX", seconds, rest @ ..] => match parse_number(seconds)? {
+                    0 => return Err(CommandError::InvalidExpireTime),
+                    seconds => (Some(seconds), rest),
+                },
+                _ => (None, rest),
+            };
+            let (rest, properties) = match (rest, json) {
+                ([rest @ .., "PROPS"], Some(json)) => (rest, Some(parse_json(json)?)),
+                (rest, None) => (rest, None),
+                _ => return Err(CommandError::Syntax),
+            };
+            Ok(Command::GeoAdd {
+                collection: collection.to_string(),
                 key: key.to_string(),
-                coords,
+                geometry: parse_geometry(rest, axis)?,
+                properties,
+                condition,
+                expire_seconds,
             })
         }
-        ["GEOSEARCH", lat, lon, radius] => Some(Command::GeoSearch {
-            lat: lat.parse().ok()?,
-            lon: lon.parse().ok()?,
-            radius: radius.parse().ok()?,
+        (["GEOSETPROPS", collection, key], Some(json)) => Ok(Command::GeoSetProps {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            properties: parse_json(json)?,
         }),
-        ["GEOGET", key] => Some(Command::GeoGet {
+        (_, Some(_)) => Err(CommandError::Syntax),
+        (parts, None) => parse_plain_command(parts, axis),
+    }
+}
+
+/// Parses the commands that take no JSON payload.
+fn parse_plain_command(parts: &[&str], axis: AxisOrder) -> Result<Command, CommandError> {
+    match parts {
+        ["GEOCREATE", collection] => Ok(Command::CreateCollection {
+            collection: collection.to_string(),
+        }),
+        ["GEODROP", collection] => Ok(Command::DropCollection {
+            collection: collection.to_string(),
+        }),
+        ["GEOCOLLECTIONS"] => Ok(Command::ListCollections),
+        ["GEOSEARCH", collection, first, second, radius, rest @ ..] => {
+            let (lat, lon) = parse_pair(first, second, axis)?;
+            let (unit, rest) = parse_unit(rest);
+            let mut fields = HitFields::default();
+            let mut options = SearchOptions::default();
+            let mut filters = Vec::new();
+            // Options come in any order; `WHERE` takes the rest of the line.
+            let mut rest = rest;
+            loop {
+                rest = match rest {
+                    [] => break,
+                    ["WHERE", conditions @ ..] => {
+                        filters = parse_filters(conditions)?;
+                        break;
+                    }
+                    ["WITHDIST", rest @ ..] => {
+                        fields.distance = true;
+                        rest
+                    }
+                    ["WITHCOORD", rest @ ..] => {
+                        fields.coord = true;
+                        rest
+                    }
+                    ["WITHTYPE", rest @ ..] => {
+                        fields.geometry_type = true;
+                        rest
+                    }
+                    ["WITHPROPS", rest @ ..] => {
+                        fields.properties = true;
+                        rest
+                    }
+                    ["ASC", rest @ ..] => {
+                        options.order = Some(SortOrder::Asc);
+                        rest
+                    }
+                    ["DESC", rest @ ..] => {
+                        options.order = Some(SortOrder::Desc);
+                        rest
+                    }
+                    ["COUNT", count, rest @ ..] => {
+                        options.count = match parse_number(count)? {
+                            0 => return Err(CommandError::InvalidNumber(count.to_string())),
+                            count => Some(count),
+                        };
+                        match rest {
+                            ["ANY", rest @ ..] => {
+                                options.any = true;
+                                rest
+                            }
+                            _ => rest,
+                        }
+                    }
+                    _ => return Err(CommandError::Syntax),
+                };
+            }
+            Ok(Command::GeoSearch {
+                collection: collection.to_string(),
+                lat,
+                lon,
+                radius: unit.to_metres(parse_number(radius)?),
+                unit,
+                fields,
+                options,
+                filters,
+            })
+        }
+        ["GEOSEARCHBOX", collection, first_min, second_min, first_max, second_max, rest @ ..] => {
+            let (min_lat, min_lon) = parse_pair(first_min, second_min, axis)?;
+            let (max_lat, max_lon) = parse_pair(first_max, second_max, axis)?;
+            validate_box(min_lat, min_lon, max_lat, max_lon)?;
+            Ok(Command::GeoSearchBox {
+                collection: collection.to_string(),
+                min_lat,
+                min_lon,
+                max_lat,
+                max_lon,
+                mode: match rest {
+                    [] | ["INTERSECTS"] => BoxMatch::Intersects,
+                    ["WITHIN"] => BoxMatch::Within,
+                    _ => return Err(CommandError::Syntax),
+                },
+            })
+        }
+        ["GEOSEARCHHASH", collection, prefix] => {
+            let cell = geohash_cell(prefix)?;
+            Ok(Command::GeoSearchBox {
+                collection: collection.to_string(),
+                min_lat: cell.min().y,
+                min_lon: cell.min().x,
+                max_lat: cell.max().y,
+                max_lon: cell.max().x,
+                mode: BoxMatch::Intersects,
+            })
+        }
+        ["GEOWITHIN", collection, rest @ ..] => Ok(Command::GeoSearchArea {
+            collection: collection.to_string(),
+            area: parse_area(rest, axis)?,
+            mode: AreaMatch::Within,
+        }),
+        ["GEOINTERSECTS", collection, rest @ ..] => Ok(Command::GeoSearchArea {
+            collection: collection.to_string(),
+            area: parse_area(rest, axis)?,
+            mode: AreaMatch::Intersects,
+        }),
+        ["GEOCONTAINS", collection, rest @ ..] => Ok(Command::GeoSearchArea {
+            collection: collection.to_string(),
+            area: parse_area(rest, axis)?,
+            mode: AreaMatch::Contains,
+        }),
+        ["GEOCONTAINING", collection, first, second] => {
+            let (lat, lon) = parse_pair(first, second, axis)?;
+            Ok(Command::GeoContaining {
+                collection: collection.to_string(),
+                lat,
+                lon,
+            })
+        }
+        ["GEOCONTAININGBATCH", collection, rest @ ..] if !rest.is_empty() => {
+            Ok(Command::GeoContainingBatch {
+                collection: collection.to_string(),
+                points: parse_pairs(rest, axis)?,
+            })
+        }
+        ["GEONEAREST", collection, first, second, k, rest @ ..] => {
+            let (lat, lon) = parse_pair(first, second, axis)?;
+            let (max_distance, rest) = match rest {
+                ["MAXDIST", max_distance, rest @ ..] => (Some(parse_number(max_distance)?), rest),
+                _ => (None, rest),
+            };
+            let (unit, rest) = parse_unit(rest);
+            if !rest.is_empty() {
+                return Err(CommandError::Syntax);
+            }
+            Ok(Command::GeoNearest {
+                collection: collection.to_string(),
+                lat,
+                lon,
+                k: parse_number(k)?,
+                max_distance: max_distance.map(|distance| unit.to_metres(distance)),
+                unit,
+            })
+        }
+        ["GEODIST", collection, key1, key2, rest @ ..] => {
+            let (unit, rest) = parse_unit(rest);
+            let method = match rest {
+                [] => DistanceMethod::default(),
+                ["METHOD", method] => method.parse().map_err(|_| CommandError::Syntax)?,
+                _ => return Err(CommandError::Syntax),
+            };
+            Ok(Command::GeoDist {
+                collection: collection.to_string(),
+                key1: key1.to_string(),
+                key2: key2.to_string(),
+                unit,
+                method,
+            })
+        }
+        ["GEOGET", collection, key] => Ok(Command::GeoGet {
+            collection: collection.to_string(),
             key: key.to_string(),
         }),
-        ["HEARTBEAT"] => Some(Command::Heartbeat),
-        _ => None,
+        ["GEOHASH", collection, key, rest @ ..] => {
+            let precision = match rest {
+                [] => DEFAULT_GEOHASH_PRECISION,
+                [precision] => parse_in_range(precision, "geohash precision", 1, 12)? as usize,
+                _ => return Err(CommandError::Syntax),
+            };
+            Ok(Command::GeoHash {
+                collection: collection.to_string(),
+                key: key.to_string(),
+                precision,
+            })
+        }
+        ["GEOH3INDEX", collection, resolution] => Ok(Command::CreateH3Index {
+            collection: collection.to_string(),
+            resolution: parse_resolution(resolution)?,
+        }),
+        ["GEOH3DROPINDEX", collection] => Ok(Command::DropH3Index {
+            collection: collection.to_string(),
+        }),
+        ["GEOH3CELL", collection, key, rest @ ..] => Ok(Command::GeoH3Cell {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            resolution: parse_optional_resolution(rest)?,
+        }),
+        ["GEOH3KEYS", collection, cell, rest @ ..] => Ok(Command::GeoH3Keys {
+            collection: collection.to_string(),
+            cell: cell
+                .parse()
+                .map_err(|_| CommandError::InvalidCell(cell.to_string()))?,
+            k: match rest {
+                [] => 0,
+                [k] => parse_in_range(k, "k", 0, MAX_GRID_DISK_K as u64)? as u32,
+                _ => return Err(CommandError::Syntax),
+            },
+        }),
+        ["GEOH3POLYFILL", collection, key, rest @ ..] => Ok(Command::GeoH3Polyfill {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            resolution: parse_optional_resolution(rest)?,
+        }),
+        ["GEODEL", collection, keys @ ..] if !keys.is_empty() => Ok(Command::GeoDel {
+            collection: collection.to_string(),
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+        }),
+        ["GEOEXPIRE", collection, key, seconds] => Ok(Command::GeoExpire {
+            collection: collection.to_string(),
+            key: key.to_string(),
+            seconds: parse_number(seconds)?,
+        }),
+        ["GEOTTL", collection, key] => Ok(Command::GeoTtl {
+            collection: collection.to_string(),
+            key: key.to_string(),
+        }),
+        ["GEOINDEX", collection, field] => Ok(Command::CreateIndex {
+            collection: collection.to_string(),
+            field: field.to_string(),
+        }),
+        ["GEODROPINDEX", collection, field] => Ok(Command::DropIndex {
+            collection: collection.to_string(),
+            field: field.to_string(),
+        }),
+        ["GEOINDEXES", collection] => Ok(Command::ListIndexes {
+            collection: collection.to_string(),
+        }),
+        ["AXIS", "LATLON"] => Ok(Command::Axis {
+            order: AxisOrder::LatLon,
+        }),
+        ["AXIS", "LONLAT"] => Ok(Command::Axis {
+            order: AxisOrder::LonLat,
+        }),
+        ["SAVE"] => Ok(Command::Save),
+        ["BGSAVE"] => Ok(Command::BgSave),
+        ["LASTSAVE"] => Ok(Command::LastSave),
+        ["INFO"] => Ok(Command::Info),
+        ["HEARTBEAT"] => Ok(Command::Heartbeat),
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+/// Parses `field op value [AND field op value ...]`. A value that is not a
+/// JSON scalar (`4.5`, `true`, `"two words"`, ...) is taken as a bare string.
+fn parse_filters(tokens: &[&str]) -> Result<Vec<Filter>, CommandError> {
+    tokens
+        .split(|token| *token == "AND")
+        .map(|condition| match condition {
+            [field, op, value] => Ok(Filter {
+                field: field.to_string(),
+                op: op.parse::<CompareOp>().map_err(|_| CommandError::Syntax)?,
+                value: serde_json::from_str::<Value>(value)
+                    .map_or_else(|_| PropValue::String(value.to_string()), PropValue::from),
+            }),
+            _ => Err(CommandError::Syntax),
+        })
+        .collect()
+}
+
+/// Takes an optional distance unit off the front of `tokens`, metres if there
+/// is none.
+fn parse_unit<'a, 'b>(tokens: &'a [&'b str]) -> (DistanceUnit, &'a [&'b str]) {
+    match tokens.split_first() {
+        Some((first, rest)) => match first.parse() {
+            Ok(unit) => (unit, rest),
+            Err(()) => (DistanceUnit::Metres, tokens),
+        },
+        None => (DistanceUnit::Metres, tokens),
+    }
+}
+
+/// Parses a whole number and checks it lies in `min..=max`.
+fn parse_in_range(
+    token: &str,
+    name: &'static str,
+    min: u64,
+    max: u64,
+) -> Result<u64, CommandError> {
+    match parse_number(token)? {
+        value if (min..=max).contains(&value) => Ok(value),
+        value => Err(CommandError::OutOfRange {
+            name,
+            value,
+            min,
+            max,
+        }),
+    }
+}
+
+fn parse_resolution(token: &str) -> Result<Resolution, CommandError> {
+    let resolution = parse_in_range(token, "H3 resolution", 0, 15)?;
+    Ok(Resolution::try_from(resolution as u8).unwrap())
+}
+
+/// An H3 resolution that may be left out.
+fn parse_optional_resolution(rest: &[&str]) -> Result<Option<Resolution>, CommandError> {
+    match rest {
+        [] => Ok(None),
+        [resolution] => Ok(Some(parse_resolution(resolution)?)),
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+fn parse_json(json: &str) -> Result<Properties, CommandError> {
+    parse_properties(json).ok_or(CommandError::InvalidProperties)
+}
+
+fn parse_number<T: FromStr>(token: &str) -> Result<T, CommandError> {
+    token
+        .parse()
+        .map_err(|_| CommandError::InvalidNumber(token.to_string()))
+}
+
+/// Parses a coordinate pair written in `axis` order into (lat, lon), and
+/// checks it is finite and in range.
+fn parse_pair(first: &str, second: &str, axis: AxisOrder) -> Result<(f64, f64), CommandError> {
+    let (lat, lon) = axis.to_lat_lon((parse_number(first)?, parse_number(second)?));
+    validate_lat_lon(lat, lon)?;
+    Ok((lat, lon))
+}
+
+/// Parses a flat list of coordinates into (lat, lon) pairs. Every token has
+/// to be a number and the count has to be even.
+fn parse_pairs(tokens: &[&str], axis: AxisOrder) -> Result<Vec<(f64, f64)>, CommandError> {
+    if !tokens.len().is_multiple_of(2) {
+        return Err(CommandError::UnpairedCoordinate);
     }
+    tokens
+        .chunks(2)
+        .map(|chunk| parse_pair(chunk[0], chunk[1], axis))
+        .collect()
+}
+
+/// Parses the geometry of a GEOADD: either WKT, which names the geometry type
+/// explicitly (`POLYGON((lat lon, ...), (...))`), a lone geohash, which stands
+/// for the point at the centre of its cell, or the untyped shorthand of
+/// `lat lon` pairs.
+fn parse_geometry(rest: &[&str], axis: AxisOrder) -> Result<Geometry<f64>, CommandError> {
+    match rest {
+        // A single pair is two tokens and WKT needs parentheses, so a lone
+        // token can only be a geohash, even one of digits alone.
+        [hash] if !hash.contains('(') => Ok(geometry_from_geohash(hash)?),
+        [first, ..] if first.parse::<f64>().is_err() => {
+            Ok(geometry_from_wkt(&rest.join(" "), axis)?)
+        }
+        _ => Ok(geometry_from_coords(&parse_pairs(rest, axis)?)?),
+    }
+}
+
+/// Parses a query polygon given either as WKT (`POLYGON((lat lon, ...))`) or as
+/// a flat list of at least three `lat lon` pairs, and checks it is valid.
+fn parse_area(rest: &[&str], axis: AxisOrder) -> Result<Polygon<f64>, CommandError> {
+    let area = match rest.first() {
+        Some(first) if first.to_uppercase().starts_with("POLYGON") => {
+            polygon_from_wkt(&rest.join(" "), axis)?
+        }
+        Some(_) => {
+            let coords = parse_pairs(rest, axis)?;
+            if coords.len() < 3 {
+                return Err(GeometryError::RingTooShort.into());
+            }
+            polygon_from_lat_lon(&coords)
+        }
+        None => return Err(CommandError::Syntax),
+    };
+    validate_polygon(&area)?;
+    Ok(area)
 }
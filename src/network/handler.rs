@@ -1,97 +1,765 @@
-use crate::network::command::{parse_command, Command};
+use crate::collections::unshare;
+use crate::geospatial::{
+    geometry_geohash, geometry_position, geometry_type, AxisOrder, DistanceUnit,
+};
+use crate::h3::{NO_H3_INDEX, NO_RESOLUTION};
+use crate::network::command::{parse_command, Command, HitFields};
 use crate::network::replica::{Replica, Role};
-use crate::persistence::WalEntry;
+use crate::persistence::{self, WalEntry};
+use crate::properties::properties_to_json;
+use crate::storage::{
+    expiry_deadline, AddOutcome, GeoDatabase, KeyTtl, SearchHit, INVALID_EXPIRE_TIME,
+};
 use log::{error, info};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 
+/// The longest command line a client may send, newline included. A longer
+/// one gets an error and the connection is closed, as the rest of it cannot
+/// be told apart from the next command.
+pub const MAX_COMMAND_LEN: usize = 1 << 20;
+
 pub async fn handle_client(
-    mut stream: TcpStream,
+    stream: TcpStream,
     replica: Arc<Replica>,
     leader_addr: Option<SocketAddr>,
 ) {
     info!("Client connected: {}", stream.peer_addr().unwrap());
-    let mut buffer = [0; 1024];
+    let mut stream = BufReader::new(stream);
+    let mut line = Vec::new();
+    // Set per connection with `AXIS LATLON|LONLAT`.
+    let mut axis = AxisOrder::default();
 
     loop {
-        let n = match stream.read(&mut buffer).await {
-            Ok(n) if n == 0 => {
-                info!("Client disconnected: {}", stream.peer_addr().unwrap());
+        line.clear();
+        let mut limited = (&mut stream).take(MAX_COMMAND_LEN as u64);
+        match limited.read_until(b'\n', &mut line).await {
+            Ok(0) => {
+                info!(
+                    "Client disconnected: {}",
+                    stream.get_ref().peer_addr().unwrap()
+                );
+                break;
+            }
+            Ok(n) if n == MAX_COMMAND_LEN && !line.ends_with(b"\n") => {
+                error!(
+                    "Command longer than {} bytes, closing connection",
+                    MAX_COMMAND_LEN
+                );
+                let response = format!("ERROR command longer than {} bytes\n", MAX_COMMAND_LEN);
+                let _ = stream.get_mut().write_all(response.as_bytes()).await;
                 break;
             }
-            Ok(n) => n,
+            Ok(_) => {}
             Err(e) => {
                 error!("Failed to read from socket; err = {:?}", e);
                 break;
             }
-        };
+        }
 
-        let input = String::from_utf8_lossy(&buffer[..n]);
+        // Always newline-terminated, also when forwarded to the leader.
+        let input = String::from_utf8_lossy(&line).trim_end().to_string() + "\n";
         info!("Received command: {}", input.trim());
-        let response = if let Some(command) = parse_command(&input) {
-            match command {
-                Command::GeoAdd { key, coords } => {
-                    let new_coord = coords.clone();
+        let parsed = parse_command(&input, axis);
+        if let (Ok(command), Role::Leader) = (&parsed, &replica.role) {
+            if let Some(collection) = command.written_collection() {
+                unshare(&replica.collections, collection);
+            }
+        }
+        let response = match parsed {
+            Ok(command) => match command {
+                Command::CreateCollection { collection } => {
+                    if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        if collections.create_collection(&collection) {
+                            let mut persistence = replica.persistence.lock().unwrap();
+                            if let Err(e) = persistence.log_entry(WalEntry::CreateCollection {
+                                collection: collection.clone(),
+                            }) {
+                                error!("Failed to log entry; err = {:?}", e);
+                            }
+                            info!(
+                                "CreateCollection command processed: collection={}",
+                                collection
+                            );
+                            "OK\n".to_string()
+                        } else {
+                            info!("CreateCollection command: collection={} exists", collection);
+                            "EXISTS\n".to_string()
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::DropCollection { collection } => {
+                    if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        if collections.drop_collection(&collection) {
+                            let mut persistence = replica.persistence.lock().unwrap();
+                            if let Err(e) = persistence.log_entry(WalEntry::DropCollection {
+                                collection: collection.clone(),
+                            }) {
+                                error!("Failed to log entry; err = {:?}", e);
+                            }
+                            info!(
+                                "DropCollection command processed: collection={}",
+                                collection
+                            );
+                            "OK\n".to_string()
+                        } else {
+                            info!(
+                                "DropCollection command: collection={} not found",
+                                collection
+                            );
+                            "Not Found\n".to_string()
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::ListCollections => {
+                    let collections = replica.collections.lock().unwrap();
+                    let names = collections.collection_names();
+                    info!("ListCollections command processed: collections={:?}", names);
+                    names
+                        .into_iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                        + "\n"
+                }
+                Command::GeoAdd {
+                    collection,
+                    key,
+                    geometry,
+                    properties,
+                    condition,
+                    expire_seconds,
+                } => {
+                    let new_geometry = geometry.clone();
 
+                    // Checked before the lock is taken, like every other argument.
+                    let expires_at = expire_seconds.map(expiry_deadline);
+                    if expires_at == Some(None) {
+                        format!("ERROR {}\n", INVALID_EXPIRE_TIME)
+                    } else if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        let held = collections
+                            .get(&collection)
+                            .is_ok_and(|db| db.holds_key(&key));
+                        match collections.geo_add_if(&collection, key.clone(), geometry, condition)
+                        {
+                            Ok(outcome) => {
+                                if outcome != AddOutcome::Skipped {
+                                    let db = collections.get_mut(&collection).unwrap();
+                                    if let Some(properties) = &properties {
+                                        db.geo_set_props(&key, properties.clone());
+                                    }
+                                    let expires_at = expires_at.flatten();
+                                    if let Some(at) = expires_at {
+                                        db.geo_expire_at(&key, at);
+                                    }
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    // A key held but created anew had expired and was
+                                    // deleted first, properties and all; replay has to
+                                    // do the same.
+                                    if held && outcome == AddOutcome::Created {
+                                        if let Err(e) = persistence.log_entry(WalEntry::GeoDel {
+                                            collection: collection.clone(),
+                                            keys: vec![key.clone()],
+                                        }) {
+                                            error!("Failed to log entry; err = {:?}", e);
+                                        }
+                                    }
+                                    if let Err(e) = persistence.log_entry(WalEntry::GeoAdd {
+                                        collection: collection.clone(),
+                                        key: key.clone(),
+                                        geometry: new_geometry,
+                                        properties,
+                                        expires_at,
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                }
+                                info!(
+                                    "GeoAdd command processed: collection={}, key={}, outcome={:?}",
+                                    collection, key, outcome
+                                );
+                                match outcome {
+                                    AddOutcome::Created => "CREATED\n",
+                                    AddOutcome::Updated => "UPDATED\n",
+                                    AddOutcome::Skipped => "SKIPPED\n",
+                                }
+                                .to_string()
+                            }
+                            Err(e) => {
+                                error!("GeoAdd rejected: key={}, err={}", key, e);
+                                format!("ERROR {}\n", e)
+                            }
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::GeoDel { collection, keys } => {
                     if let Role::Leader = replica.role {
-                        let mut db = replica.db.lock().unwrap();
-                        db.geo_add(key.clone(), coords);
+                        let mut collections = replica.collections.lock().unwrap();
+                        match collections.get_mut(&collection) {
+                            Ok(db) => {
+                                let deleted: Vec<String> =
+                                    keys.into_iter().filter(|key| db.geo_del(key)).collect();
 
-                        let mut persistence = replica.persistence.lock().unwrap();
-                        if let Err(e) = persistence.log_entry(WalEntry::GeoAdd {
-                            key: key.clone(),
-                            coords: new_coord,
-                        }) {
-                            error!("Failed to log entry; err = {:?}", e);
-                        }
-                        info!("GeoAdd command processed: key={}", key);
-                        "OK\n".to_string()
+                                if !deleted.is_empty() {
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    if let Err(e) = persistence.log_entry(WalEntry::GeoDel {
+                                        collection: collection.clone(),
+                                        keys: deleted.clone(),
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                }
+                                info!(
+                                    "GeoDel command processed: collection={}, keys={:?}",
+                                    collection, deleted
+                                );
+                                format!("{}\n", deleted.len())
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        }
                     } else {
-                        // Forward write requests to the leader
-                        if let Some(leader_addr) = leader_addr {
-                            if let Ok(mut leader_stream) = TcpStream::connect(leader_addr).await {
-                                // TODO: Do we actually need to connect to the leader in every request?, heartbeat can ensure that we are connected to the leader.
-                                leader_stream.write_all(input.as_bytes()).await.unwrap();
-                                let mut leader_response = [0; 1024];
-                                let n = leader_stream.read(&mut leader_response).await.unwrap();
-                                String::from_utf8_lossy(&leader_response[..n]).to_string()
-                            } else {
-                                error!("Failed to connect to leader at {}", leader_addr);
-                                "ERROR\n".to_string()
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::GeoExpire {
+                    collection,
+                    key,
+                    seconds,
+                } => match expiry_deadline(seconds) {
+                    None => format!("ERROR {}\n", INVALID_EXPIRE_TIME),
+                    Some(at) if replica.role == Role::Leader => {
+                        let mut collections = replica.collections.lock().unwrap();
+                        match collections.get_mut(&collection) {
+                            Ok(db) => {
+                                if db.geo_expire_at(&key, at) {
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    if let Err(e) = persistence.log_entry(WalEntry::GeoExpire {
+                                        collection: collection.clone(),
+                                        key: key.clone(),
+                                        at,
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                    info!(
+                                        "GeoExpire command processed: key={}, seconds={}",
+                                        key, seconds
+                                    );
+                                    "OK\n".to_string()
+                                } else {
+                                    info!("GeoExpire command: key={} not found", key);
+                                    "Not Found\n".to_string()
+                                }
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        }
+                    }
+                    Some(_) => forward_to_leader(&input, leader_addr, axis).await,
+                },
+                Command::GeoTtl { collection, key } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => {
+                            let ttl = db.geo_ttl(&key);
+                            info!("GeoTtl command processed: key={}, ttl={:?}", key, ttl);
+                            // Redis conventions: -2 for a missing key, -1 for no expiry.
+                            match ttl {
+                                KeyTtl::Missing => "-2\n".to_string(),
+                                KeyTtl::Persistent => "-1\n".to_string(),
+                                KeyTtl::ExpiresIn(remaining) => {
+                                    format!("{}\n", remaining.as_millis().div_ceil(1000))
+                                }
                             }
-                        } else {
-                            error!("Leader address not provided.");
-                            "ERROR\n".to_string()
                         }
+                        Err(e) => format!("ERROR {}\n", e),
                     }
                 }
-                Command::GeoSearch { lat, lon, radius } => {
-                    let db = replica.db.lock().unwrap();
-                    let results = db.geo_search(lat, lon, radius);
-                    info!(
-                        "GeoSearch command processed: lat={}, lon={}, radius={}",
-                        lat, lon, radius
-                    );
-                    results.join("\n") + "\n"
+                Command::GeoSetProps {
+                    collection,
+                    key,
+                    properties,
+                } => {
+                    if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        match collections.get_mut(&collection) {
+                            Ok(db) => {
+                                if db.geo_set_props(&key, properties.clone()) {
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    if let Err(e) = persistence.log_entry(WalEntry::GeoSetProps {
+                                        collection: collection.clone(),
+                                        key: key.clone(),
+                                        properties,
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                    info!("GeoSetProps command processed: key={}", key);
+                                    "OK\n".to_string()
+                                } else {
+                                    info!("GeoSetProps command: key={} not found", key);
+                                    "Not Found\n".to_string()
+                                }
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
                 }
-                Command::GeoGet { key } => {
-                    let db = replica.db.lock().unwrap();
-                    match db.geo_get(&key) {
-                        Some(data) => {
-                            info!("GeoGet command processed: key={}", key);
-                            data + "\n"
+                Command::GeoSearch {
+                    collection,
+                    lat,
+                    lon,
+                    radius,
+                    unit,
+                    fields,
+                    options,
+                    filters,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => {
+                            let results: Vec<String> = db
+                                .geo_search_hits(lat, lon, radius, &filters, &options)
+                                .iter()
+                                .map(|hit| search_line(db, hit, fields, unit, axis))
+                                .collect();
+                            info!(
+                                "GeoSearch command processed: collection={}, lat={}, lon={}, radius={}",
+                                collection, lat, lon, radius
+                            );
+                            results.join("\n") + "\n"
                         }
-                        None => {
-                            info!("GeoGet command: key={} not found", key);
-                            "Not Found\n".to_string()
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoSearchBox {
+                    collection,
+                    min_lat,
+                    min_lon,
+                    max_lat,
+                    max_lon,
+                    mode,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => match db.geo_search_box(min_lat, min_lon, max_lat, max_lon, mode)
+                        {
+                            Ok(results) => {
+                                info!(
+                                    "GeoSearchBox command processed: min_lat={}, min_lon={}, max_lat={}, max_lon={}, mode={:?}",
+                                    min_lat, min_lon, max_lat, max_lon, mode
+                                );
+                                results.join("\n") + "\n"
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        },
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoSearchArea {
+                    collection,
+                    area,
+                    mode,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => {
+                            let results = db.geo_search_area(&area, mode);
+                            info!("GeoSearchArea command processed: mode={:?}", mode);
+                            results.join("\n") + "\n"
                         }
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoContaining {
+                    collection,
+                    lat,
+                    lon,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => {
+                            let results = db.geo_containing(lat, lon);
+                            info!("GeoContaining command processed: lat={}, lon={}", lat, lon);
+                            results.join("\n") + "\n"
+                        }
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoContainingBatch { collection, points } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => {
+                            // One line per queried point, listing the containing keys.
+                            let lines: Vec<String> = points
+                                .iter()
+                                .map(|&(lat, lon)| db.geo_containing(lat, lon).join(" "))
+                                .collect();
+                            info!(
+                                "GeoContainingBatch command processed: points={}",
+                                points.len()
+                            );
+                            lines.join("\n") + "\n"
+                        }
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoNearest {
+                    collection,
+                    lat,
+                    lon,
+                    k,
+                    max_distance,
+                    unit,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => {
+                            let hits = db.geo_nearest(lat, lon, k, max_distance);
+                            info!(
+                                "GeoNearest command processed: lat={}, lon={}, k={}, max_distance={:?}",
+                                lat, lon, k, max_distance
+                            );
+                            hits.iter()
+                                .map(|hit| {
+                                    format!("{} {:.4}", hit.key, unit.from_metres(hit.distance))
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                                + "\n"
+                        }
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoDist {
+                    collection,
+                    key1,
+                    key2,
+                    unit,
+                    method,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => match db.geo_dist(&key1, &key2, method) {
+                            Some(Ok(distance)) => {
+                                info!(
+                                    "GeoDist command processed: key1={}, key2={}, method={:?}",
+                                    key1, key2, method
+                                );
+                                format!("{:.4}\n", unit.from_metres(distance))
+                            }
+                            Some(Err(e)) => format!("ERROR {}\n", e),
+                            None => "Not Found\n".to_string(),
+                        },
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoGet { collection, key } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => match db.geo_get(&key, axis) {
+                            Some(data) => {
+                                info!("GeoGet command processed: key={}", key);
+                                match db.geo_props(&key) {
+                                    Some(properties) if !properties.is_empty() => {
+                                        format!("{} {}\n", data, properties_to_json(properties))
+                                    }
+                                    _ => data + "\n",
+                                }
+                            }
+                            None => {
+                                info!("GeoGet command: key={} not found", key);
+                                "Not Found\n".to_string()
+                            }
+                        },
+                        Err(e) => format!("ERROR {}\n", e),
                     }
                 }
+                Command::GeoHash {
+                    collection,
+                    key,
+                    precision,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => match db.geo_geometry(&key) {
+                            Some(geometry) => match geometry_geohash(geometry, precision) {
+                                Ok(hash) => {
+                                    info!("GeoHash command processed: key={}", key);
+                                    hash + "\n"
+                                }
+                                Err(e) => format!("ERROR {}\n", e),
+                            },
+                            None => "Not Found\n".to_string(),
+                        },
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::CreateH3Index {
+                    collection,
+                    resolution,
+                } => {
+                    if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        match collections.get_mut(&collection) {
+                            Ok(db) => {
+                                if db.create_h3_index(resolution) {
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    if let Err(e) = persistence.log_entry(WalEntry::CreateH3Index {
+                                        collection: collection.clone(),
+                                        resolution,
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                }
+                                info!("CreateH3Index command processed: resolution={}", resolution);
+                                "OK\n".to_string()
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::DropH3Index { collection } => {
+                    if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        match collections.get_mut(&collection) {
+                            Ok(db) => {
+                                if db.drop_h3_index() {
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    if let Err(e) = persistence.log_entry(WalEntry::DropH3Index {
+                                        collection: collection.clone(),
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                    info!(
+                                        "DropH3Index command processed: collection={}",
+                                        collection
+                                    );
+                                    "OK\n".to_string()
+                                } else {
+                                    "Not Found\n".to_string()
+                                }
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::GeoH3Cell {
+                    collection,
+                    key,
+                    resolution,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => match resolution.or(db.h3_resolution()) {
+                            Some(resolution) => match db.geo_h3_cell(&key, resolution) {
+                                Some(cell) => {
+                                    info!("GeoH3Cell command processed: key={}", key);
+                                    format!("{}\n", cell)
+                                }
+                                None => "Not Found\n".to_string(),
+                            },
+                            None => format!("ERROR {}\n", NO_RESOLUTION),
+                        },
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoH3Keys {
+                    collection,
+                    cell,
+                    k,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => match db.geo_h3_keys(cell, k) {
+                            Some(keys) => {
+                                info!("GeoH3Keys command processed: cell={}, k={}", cell, k);
+                                keys.join("\n") + "\n"
+                            }
+                            None => format!("ERROR {}\n", NO_H3_INDEX),
+                        },
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::GeoH3Polyfill {
+                    collection,
+                    key,
+                    resolution,
+                } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => match resolution.or(db.h3_resolution()) {
+                            Some(resolution) => match db.geo_h3_polyfill(&key, resolution) {
+                                Some(Ok(cells)) => {
+                                    info!(
+                                        "GeoH3Polyfill command processed: key={}, cells={}",
+                                        key,
+                                        cells.len()
+                                    );
+                                    cells
+                                        .iter()
+                                        .map(|cell| cell.to_string())
+                                        .collect::<Vec<_>>()
+                                        .join("\n")
+                                        + "\n"
+                                }
+                                Some(Err(e)) => format!("ERROR {}\n", e),
+                                None => "Not Found\n".to_string(),
+                            },
+                            None => format!("ERROR {}\n", NO_RESOLUTION),
+                        },
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::CreateIndex { collection, field } => {
+                    if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        match collections.get_mut(&collection) {
+                            Ok(db) => {
+                                if db.create_index(&field) {
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    if let Err(e) = persistence.log_entry(WalEntry::CreateIndex {
+                                        collection: collection.clone(),
+                                        field: field.clone(),
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                }
+                                info!("CreateIndex command processed: field={}", field);
+                                "OK\n".to_string()
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::DropIndex { collection, field } => {
+                    if let Role::Leader = replica.role {
+                        let mut collections = replica.collections.lock().unwrap();
+                        match collections.get_mut(&collection) {
+                            Ok(db) => {
+                                if db.drop_index(&field) {
+                                    let mut persistence = replica.persistence.lock().unwrap();
+                                    if let Err(e) = persistence.log_entry(WalEntry::DropIndex {
+                                        collection: collection.clone(),
+                                        field: field.clone(),
+                                    }) {
+                                        error!("Failed to log entry; err = {:?}", e);
+                                    }
+                                    info!("DropIndex command processed: field={}", field);
+                                    "OK\n".to_string()
+                                } else {
+                                    info!("DropIndex command: field={} not indexed", field);
+                                    "Not Found\n".to_string()
+                                }
+                            }
+                            Err(e) => format!("ERROR {}\n", e),
+                        }
+                    } else {
+                        forward_to_leader(&input, leader_addr, axis).await
+                    }
+                }
+                Command::ListIndexes { collection } => {
+                    let collections = replica.collections.lock().unwrap();
+                    match collections.get(&collection) {
+                        Ok(db) => {
+                            let fields = db.indexed_fields();
+                            info!("ListIndexes command processed: fields={:?}", fields);
+                            fields
+                                .into_iter()
+                                .map(String::as_str)
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                                + "\n"
+                        }
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::Axis { order } => {
+                    axis = order;
+                    info!("Axis command processed: order={:?}", order);
+                    "OK\n".to_string()
+                }
+                Command::Save => {
+                    // Writing the snapshot is blocking I/O, so it runs on a
+                    // blocking thread like `BGSAVE`'s, only awaited.
+                    let collections = Arc::clone(&replica.collections);
+                    let persistence = Arc::clone(&replica.persistence);
+                    let result = tokio::task::spawn_blocking(move || {
+                        persistence::save(&collections, &persistence)
+                    })
+                    .await
+                    .unwrap();
+                    match result {
+                        Ok(seq) => {
+                            info!("Save command processed: seq={}", seq);
+                            "OK\n".to_string()
+                        }
+                        Err(e) => {
+                            error!("Failed to create snapshot; err = {:?}", e);
+                            format!("ERROR {}\n", e)
+                        }
+                    }
+                }
+                Command::BgSave => {
+                    match persistence::background_save(&replica.collections, &replica.persistence) {
+                        Ok(()) => {
+                            info!("BgSave command processed");
+                            "Background saving started\n".to_string()
+                        }
+                        Err(e) => format!("ERROR {}\n", e),
+                    }
+                }
+                Command::LastSave => {
+                    let last_save = replica.persistence.lock().unwrap().last_save();
+                    info!("LastSave command processed: last_save={}", last_save);
+                    format!("{}\n", last_save)
+                }
+                Command::Info => {
+                    let mut info = {
+                        let collections = replica.collections.lock().unwrap();
+                        vec![
+                            (
+                                "role",
+                                match replica.role {
+                                    Role::Leader => "leader",
+                                    Role::Replica => "replica",
+                                }
+                                .to_string(),
+                            ),
+                            (
+                                "collections",
+                                collections.collection_names().len().to_string(),
+                            ),
+                            ("keys", collections.key_count().to_string()),
+                        ]
+                    };
+                    info.extend(replica.persistence.lock().unwrap().info());
+                    info!("Info command processed");
+                    info.iter()
+                        .map(|(name, value)| format!("{}:{}", name, value))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                        + "\n"
+                }
                 Command::Heartbeat => {
                     if let Role::Leader = replica.role {
-                        if let Ok(addr) = stream.peer_addr() {
+                        if let Ok(addr) = stream.get_ref().peer_addr() {
                             replica.handle_heartbeat(addr).await;
                         }
                         "OK\n".to_string()
@@ -99,19 +767,86 @@ pub async fn handle_client(
                         "ERROR\n".to_string()
                     }
                 }
+            },
+            Err(e) => {
+                error!("Invalid command received: {}; err = {}", input.trim(), e);
+                format!("ERROR {}\n", e)
             }
-        } else {
-            error!("Invalid command received: {}", input.trim());
-            "ERROR\n".to_string()
         };
 
-        if let Err(e) = stream.write_all(response.as_bytes()).await {
+        if let Err(e) = stream.get_mut().write_all(response.as_bytes()).await {
             error!("Failed to write to socket; err = {:?}", e);
             break;
         }
     }
     info!(
         "Handler finished for client: {}",
-        stream.peer_addr().unwrap()
+        stream.get_ref().peer_addr().unwrap()
     );
 }
+
+/// Formats one `GEOSEARCH` hit as `key [distance] [coord] [type] [{json}]`,
+/// with only the fields asked for and the distance in `unit`. The coordinate
+/// is a point's own pair or the corners of a bounding box, comma-separated in
+/// the connection's axis order, and properties are `{}` for keys without any.
+fn search_line(
+    db: &GeoDatabase,
+    hit: &SearchHit,
+    fields: HitFields,
+    unit: DistanceUnit,
+    axis: AxisOrder,
+) -> String {
+    let mut line = hit.key.clone();
+    if fields.distance {
+        line += &format!(" {:.4}", unit.from_metres(hit.distance));
+    }
+    if let Some(geometry) = db.geo_geometry(&hit.key) {
+        if fields.coord {
+            let coords: Vec<String> = geometry_position(geometry)
+                .into_iter()
+                .map(|pair| {
+                    let (first, second) = axis.from_lat_lon(pair);
+                    format!("{},{}", first, second)
+                })
+                .collect();
+            line += &format!(" {}", coords.join(","));
+        }
+        if fields.geometry_type {
+            line += &format!(" {}", geometry_type(geometry));
+        }
+    }
+    if fields.properties {
+        let properties = db.geo_props(&hit.key).cloned().unwrap_or_default();
+        line += &format!(" {}", properties_to_json(&properties));
+    }
+    line
+}
+
+/// Forwards a write command to the leader and relays its response back. The
+/// leader connection is switched to the client's `axis` first so coordinates
+/// are read the same way they were here.
+async fn forward_to_leader(
+    input: &str,
+    leader_addr: Option<SocketAddr>,
+    axis: AxisOrder,
+) -> String {
+    if let Some(leader_addr) = leader_addr {
+        if let Ok(mut leader_stream) = TcpStream::connect(leader_addr).await {
+            // TODO: Do we actually need to connect to the leader in every request?, heartbeat can ensure that we are connected to the leader.
+            let mut leader_response = [0; 1024];
+            if axis != AxisOrder::default() {
+                leader_stream.write_all(b"AXIS LONLAT\n").await.unwrap();
+                let _ = leader_stream.read(&mut leader_response).await.unwrap();
+            }
+            leader_stream.write_all(input.as_bytes()).await.unwrap();
+            let n = leader_stream.read(&mut leader_response).await.unwrap();
+            String::from_utf8_lossy(&leader_response[..n]).to_string()
+        } else {
+            error!("Failed to connect to leader at {}", leader_addr);
+            "ERROR\n".to_string()
+        }
+    } else {
+        error!("Leader address not provided.");
+        "ERROR\n".to_string()
+    }
+}
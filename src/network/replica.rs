@@ -1,8 +1,13 @@
-use crate::persistence::Persistence;
-use crate::storage::GeoDatabase;
+use crate::collections::{unshare, GeoCollections};
+use crate::persistence::{
+    self, DataDir, Persistence, SnapshotRule, WalEntry, DEFAULT_SNAPSHOTS_KEPT,
+    DEFAULT_SNAPSHOT_RULES,
+};
+use crate::storage::now_ms;
 use log::{error, info};
 use std::collections::HashMap;
 use std::env;
+use std::io;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
@@ -21,57 +26,78 @@ pub enum Role {
 pub struct Replica {
     pub addr: SocketAddr,
     pub role: Role,
-    pub db: Arc<Mutex<GeoDatabase>>,
+    pub collections: Arc<Mutex<GeoCollections>>,
     pub persistence: Arc<Mutex<Persistence>>,
     pub leader_addr: Option<SocketAddr>,
     pub replicas: Arc<Mutex<HashMap<SocketAddr, std::time::Instant>>>, // Track replica heartbeats
+    /// When the leader snapshots, from `SNAPSHOT_RULES`.
+    snapshot_rules: Vec<SnapshotRule>,
 }
 
 impl Replica {
-    pub async fn new(addr: SocketAddr, role: Role, leader_addr: Option<SocketAddr>) -> Self {
-        let db = Arc::new(Mutex::new(GeoDatabase::new()));
-        let persistence = Arc::new(Mutex::new(Persistence::new().unwrap()));
+    /// Sets up the node's state from `data_dir`. Fails, and the server does
+    /// not start, if the directory is in use by another node, its data
+    /// cannot be recovered, `SNAPSHOTS_TO_KEEP` is not a number or
+    /// `SNAPSHOT_RULES` are not pairs of numbers.
+    pub async fn new(
+        addr: SocketAddr,
+        role: Role,
+        leader_addr: Option<SocketAddr>,
+        data_dir: &Path,
+    ) -> io::Result<Self> {
+        let snapshots_kept = match env::var("SNAPSHOTS_TO_KEEP") {
+            Ok(snapshots_kept) => snapshots_kept.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "SNAPSHOTS_TO_KEEP must be a whole number, not '{}'",
+                        snapshots_kept
+                    ),
+                )
+            })?,
+            Err(_) => DEFAULT_SNAPSHOTS_KEPT,
+        };
+        let snapshot_rules =
+            env::var("SNAPSHOT_RULES").unwrap_or(DEFAULT_SNAPSHOT_RULES.to_string());
+        let snapshot_rules = SnapshotRule::parse_rules(&snapshot_rules).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "SNAPSHOT_RULES must be pairs of seconds and changes, not '{}'",
+                    snapshot_rules
+                ),
+            )
+        })?;
+        let data_dir = DataDir::lock(data_dir).map_err(|e| context("lock data directory", e))?;
+        // Replicas start empty and get their data from the leader.
+        let (collections, persistence) = if role == Role::Leader {
+            // Starting without the snapshot or part of the WAL would lose the
+            // writes in them for good once the next snapshot is taken, and
+            // writing on top of a log that cannot be replayed would bury the
+            // damage further, so failing to recover stops the server.
+            let recovery = Persistence::recover(data_dir.path())
+                .map_err(|e| context("recover database", e))?;
+            info!("Recovered database from snapshot and write-ahead log (WAL).");
+            let persistence = Persistence::open_recovered(data_dir, &recovery);
+            (recovery.collections, persistence)
+        } else {
+            (GeoCollections::new(), Persistence::open(data_dir))
+        };
+        let mut persistence = persistence.map_err(|e| context("open write-ahead log", e))?;
+        persistence.set_snapshots_kept(snapshots_kept);
+        let collections = Arc::new(Mutex::new(collections));
+        let persistence = Arc::new(Mutex::new(persistence));
         let replicas = Arc::new(Mutex::new(HashMap::new()));
 
-        if role == Role::Leader {
-            // Load the database from snapshot, if available, otherwise create a new one
-            let snapshot_path = Path::new("snapshot.bincode");
-            let wal_path = Path::new("wal.log");
-
-            if snapshot_path.exists() {
-                match Persistence::load_snapshot() {
-                    Ok(loaded_db) => {
-                        let mut db_guard = db.lock().unwrap();
-                        *db_guard = loaded_db;
-                        info!("Loaded database from snapshot.");
-                    }
-                    Err(e) => {
-                        error!(
-                            "Failed to load snapshot, starting with a new database. Error: {:?}",
-                            e
-                        );
-                    }
-                }
-            }
-
-            if wal_path.exists() {
-                // Load WAL to recover any missed entries
-                if Persistence::load_wal(&mut db.lock().unwrap()).is_ok() {
-                    info!("Loaded write-ahead log (WAL).");
-                } else {
-                    error!("Failed to load write-ahead log (WAL).");
-                }
-            }
-        }
-
-        Replica {
+        Ok(Replica {
             addr,
             role,
-            db,
+            collections,
             persistence,
             leader_addr,
             replicas,
-        }
+            snapshot_rules,
+        })
     }
 
     pub async fn send_heartbeat(&self) {
@@ -127,9 +153,70 @@ impl Replica {
         }
     }
 
+    /// Removes expired keys from the database on a fixed interval, so keys
+    /// nobody reads again do not linger in the R-trees. The leader logs the
+    /// removals to the WAL.
+    pub async fn sweep_expired_keys(&self) {
+        let sweep_rate = env::var("EXPIRY_SWEEP_EVERY_X_SECONDS")
+            .unwrap_or("1".to_string())
+            .parse::<u64>()
+            .unwrap();
+
+        loop {
+            sleep(Duration::from_secs(sweep_rate)).await;
+            let now = now_ms();
+            let expiring = self.collections.lock().unwrap().expiring_collections(now);
+            for collection in &expiring {
+                unshare(&self.collections, collection);
+            }
+            let mut collections = self.collections.lock().unwrap();
+            for (collection, keys) in collections.purge_expired(now) {
+                info!(
+                    "Expired keys removed: collection={}, keys={:?}",
+                    collection, keys
+                );
+                if let Role::Leader = self.role {
+                    let mut persistence = self.persistence.lock().unwrap();
+                    if let Err(e) = persistence.log_entry(WalEntry::GeoDel { collection, keys }) {
+                        error!("Failed to log entry; err = {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes a snapshot whenever one of the save rules in `SNAPSHOT_RULES` is
+    /// met, which also keeps the WAL short. An empty `SNAPSHOT_RULES` turns
+    /// periodic snapshots off.
+    pub async fn snapshot_periodically(&self) {
+        if self.snapshot_rules.is_empty() {
+            return;
+        }
+
+        loop {
+            sleep(Duration::from_secs(1)).await;
+            if !self
+                .persistence
+                .lock()
+                .unwrap()
+                .snapshot_due(&self.snapshot_rules)
+            {
+                continue;
+            }
+            if let Err(e) = persistence::background_save(&self.collections, &self.persistence) {
+                error!("Failed to start snapshot; err = {:?}", e);
+            }
+        }
+    }
+
     pub async fn handle_heartbeat(&self, addr: SocketAddr) {
         info!("Heartbeat from replica at {}", addr);
         let mut replicas = self.replicas.lock().unwrap();
         replicas.insert(addr, std::time::Instant::now());
     }
 }
+
+/// Adds what was being attempted to a startup error.
+fn context(action: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("Failed to {}: {}", action, e))
+}
@@ -1,18 +1,27 @@
 use crate::api;
 use crate::network::handler::handle_client;
 use crate::network::replica::{Replica, Role};
-use crate::persistence::Persistence;
-use log::info;
+use crate::persistence;
+use log::{error, info};
+use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::signal;
 
-pub async fn start_server(addr: SocketAddr, leader_addr: Option<SocketAddr>, role: Role) {
+/// Runs a node at `addr`, keeping its WAL and snapshots in `data_dir`.
+/// Returns once the node shuts down, or right away if it cannot start.
+pub async fn start_server(
+    addr: SocketAddr,
+    leader_addr: Option<SocketAddr>,
+    role: Role,
+    data_dir: PathBuf,
+) -> io::Result<()> {
     info!("Starting server on {}...", addr);
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = TcpListener::bind(addr).await?;
 
-    let replica = Arc::new(Replica::new(addr, role.clone(), leader_addr).await);
+    let replica = Arc::new(Replica::new(addr, role.clone(), leader_addr, &data_dir).await?);
 
     if let Role::Replica = role {
         // when we say replica we mean follower
@@ -25,14 +34,24 @@ pub async fn start_server(addr: SocketAddr, leader_addr: Option<SocketAddr>, rol
         tokio::spawn(async move {
             replica_clone.monitor_replicas().await;
         });
+
+        let replica_clone = Arc::clone(&replica);
+        tokio::spawn(async move {
+            replica_clone.snapshot_periodically().await;
+        });
     }
 
+    let replica_clone = Arc::clone(&replica);
+    tokio::spawn(async move {
+        replica_clone.sweep_expired_keys().await;
+    });
+
     info!("Server is ready to accept connections.");
 
     let replica_clone = Arc::clone(&replica);
     // Initialize the REST API
     let replica = Arc::clone(&replica_clone);
-    let api = api::create_api(replica.db.clone());
+    let api = api::create_api(replica.collections.clone(), replica.persistence.clone());
     let warp_server = warp::serve(api).run(([127, 0, 0, 1], 3030));
 
     tokio::select! {
@@ -41,7 +60,6 @@ pub async fn start_server(addr: SocketAddr, leader_addr: Option<SocketAddr>, rol
             loop {
                 let (socket, _) = listener.accept().await.unwrap();
                 let replica = Arc::clone(&replica_clone);
-                let leader_addr = leader_addr.clone();
                 tokio::spawn(async move {
                     handle_client(socket, replica, leader_addr).await;
                 });
@@ -49,8 +67,16 @@ pub async fn start_server(addr: SocketAddr, leader_addr: Option<SocketAddr>, rol
         } => {},
         _ = signal::ctrl_c() => {
             info!("Creating snapshot before shutdown...");
-            Persistence::create_snapshot(&replica.db.lock().unwrap()).unwrap();
-            info!("Snapshot created, shutting down.");
+            // Hands this worker's other tasks to another one while the
+            // snapshot is written.
+            let result = tokio::task::block_in_place(|| {
+                persistence::save(&replica.collections, &replica.persistence)
+            });
+            match result {
+                Ok(_) => info!("Snapshot created, shutting down."),
+                Err(e) => error!("Failed to create snapshot; err = {:?}", e),
+            }
         }
     }
+    Ok(())
 }
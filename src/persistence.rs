@@ -1,71 +1,940 @@
-use crate::storage::GeoDatabase;
+use crate::collections::GeoCollections;
+use crate::properties::Properties;
+use crate::storage::now_ms;
+use geo::Geometry;
+use h3o::Resolution;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+// Every file below lives in the node's data directory, see `DataDir`.
 const WAL_FILE: &str = "wal.log"; // The WAL logs each write operation (e.g., adding a geospatial point) to disk.
-const SNAPSHOT_FILE: &str = "snapshot.bincode"; // A snapshot is a complete copy of the database at a certain point in time.
+/// Every WAL segment starts with these bytes, the format version, a little
+/// endian `u32`, and the sequence number of its first record, a little endian
+/// `u64`. Records follow, each the length of a bincode `WalEntry`, the CRC32
+/// of that length and the CRC32 of the entry, little endian `u32`s, then the
+/// entry itself. Records are numbered one after another across segments,
+/// starting at 1.
+const WAL_MAGIC: &[u8; 8] = b"GEOMMWAL";
+const WAL_VERSION: u32 = 3;
+const WAL_HEADER_LEN: usize = 20;
+const RECORD_HEADER_LEN: usize = 12;
+/// A snapshot is a complete copy of the database at a certain point in time,
+/// stored as `snapshot.<last WAL sequence number it covers>.bincode`. It
+/// starts with these bytes, the format version, a little endian `u32`, and
+/// the CRC32 of everything after it. Then come its creation time in Unix
+/// milliseconds, the sequence number and the number of keys, little endian
+/// `u64`s, and the bincode `GeoCollections`.
+const SNAPSHOT_PREFIX: &str = "snapshot.";
+const SNAPSHOT_SUFFIX: &str = ".bincode";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"GEOMMSNP";
+const SNAPSHOT_VERSION: u32 = 1;
+const SNAPSHOT_HEADER_LEN: usize = 40;
+
+/// Held locked by the node using the data directory, see `DataDir`.
+const LOCK_FILE: &str = "geommdb.lock";
+
+/// How long to wait before retrying a periodic snapshot that failed.
+const SNAPSHOT_RETRY_SECONDS: u64 = 5;
+
+/// The error `begin_snapshot` fails with while a snapshot is being written.
+pub const SNAPSHOT_IN_PROGRESS: &str = "a snapshot is already in progress";
+
+/// How many snapshots are kept, along with the WAL segments after the oldest
+/// of them, unless `set_snapshots_kept` says otherwise.
+pub const DEFAULT_SNAPSHOTS_KEPT: usize = 3;
+
+/// Redis's default save rules: after an hour if anything changed, five
+/// minutes after 100 changes, one minute after 10,000.
+pub const DEFAULT_SNAPSHOT_RULES: &str = "3600 1 300 100 60 10000";
 
 #[derive(Serialize, Deserialize)]
 pub enum WalEntry {
     // Make this enum public
+    CreateCollection {
+        collection: String,
+    },
+    DropCollection {
+        collection: String,
+    },
     GeoAdd {
+        collection: String,
+        key: String,
+        geometry: Geometry<f64>,
+        properties: Option<Properties>,
+        expires_at: Option<u64>,
+    },
+    GeoSetProps {
+        collection: String,
+        key: String,
+        properties: Properties,
+    },
+    GeoDel {
+        collection: String,
+        keys: Vec<String>,
+    },
+    // Deadlines are absolute Unix milliseconds, so a replay agrees with the
+    // original run no matter when it happens.
+    GeoExpire {
+        collection: String,
         key: String,
-        coords: Vec<(f64, f64)>,
+        at: u64,
+    },
+    CreateIndex {
+        collection: String,
+        field: String,
+    },
+    DropIndex {
+        collection: String,
+        field: String,
     },
+    CreateH3Index {
+        collection: String,
+        resolution: Resolution,
+    },
+    DropH3Index {
+        collection: String,
+    },
+}
+
+/// A Redis style save rule: snapshot once `after` has passed since the last
+/// snapshot, if at least `changes` entries have been logged since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotRule {
+    pub after: Duration,
+    pub changes: u64,
+}
+
+impl SnapshotRule {
+    /// Parses whitespace separated "<seconds> <changes>" pairs, such as
+    /// `DEFAULT_SNAPSHOT_RULES`. An empty string is no rules at all.
+    pub fn parse_rules(rules: &str) -> Option<Vec<SnapshotRule>> {
+        let numbers: Vec<u64> = rules
+            .split_whitespace()
+            .map(|number| number.parse().ok())
+            .collect::<Option<_>>()?;
+        if !numbers.len().is_multiple_of(2) {
+            return None;
+        }
+        Some(
+            numbers
+                .chunks(2)
+                .map(|pair| SnapshotRule {
+                    after: Duration::from_secs(pair[0]),
+                    changes: pair[1],
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A node's data directory, which holds its WAL segments and snapshots. It is
+/// locked against other processes for as long as this value lives, so two
+/// nodes pointed at the same directory cannot interleave their writes.
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl DataDir {
+    /// Creates the directory at `path` if needed and locks it. Fails with
+    /// `ResourceBusy` if another process, or another `DataDir` in this one,
+    /// holds the lock.
+    pub fn lock(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(LOCK_FILE))?;
+        match lock.try_lock() {
+            Ok(()) => Ok(DataDir { path, _lock: lock }),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!(
+                    "data directory {} is in use by another process",
+                    path.display()
+                ),
+            )),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 }
 
 pub struct Persistence {
+    data_dir: DataDir,
     wal_writer: BufWriter<File>,
+    /// The sequence number of the first record in the active WAL segment.
+    wal_first_seq: u64,
+    /// The sequence number the next logged entry gets.
+    next_seq: u64,
+    /// The last sequence number the newest snapshot covers, 0 for none.
+    snapshot_seq: u64,
+    last_snapshot: Instant,
+    /// When the newest snapshot was taken, in Unix milliseconds. Until the
+    /// first one, when the WAL was opened, as the database was then on disk.
+    last_save_ms: u64,
+    snapshots_kept: usize,
+    /// When the snapshot being written was started, if one is.
+    snapshot_started: Option<Instant>,
+    last_outcome: Option<SnapshotOutcome>,
+}
+
+/// How the last snapshot to finish went.
+struct SnapshotOutcome {
+    finished: Instant,
+    took: Duration,
+    error: Option<String>,
+}
+
+/// What `Persistence::recover` read back from a data directory: the database,
+/// and where the WAL there carries on from.
+pub struct Recovery {
+    pub collections: GeoCollections,
+    position: WalPosition,
+}
+
+/// Where the WAL in a data directory stands.
+#[derive(Debug, Clone, Copy)]
+struct WalPosition {
+    /// The last sequence number the newest snapshot covers, 0 for none.
+    snapshot_seq: u64,
+    /// The sequence number the next logged entry gets.
+    next_seq: u64,
+    /// The first sequence number of the active segment, if there is one.
+    active_first_seq: Option<u64>,
+}
+
+impl WalPosition {
+    /// The position before any WAL segment is read: just after the newest
+    /// snapshot in `dir`.
+    fn after_snapshots(dir: &Path) -> io::Result<Self> {
+        let snapshot_seq = snapshots(dir)?.last().map_or(0, |(seq, _)| *seq);
+        Ok(WalPosition {
+            snapshot_seq,
+            next_seq: snapshot_seq + 1,
+            active_first_seq: None,
+        })
+    }
+
+    /// Moves the position past `segment`.
+    fn extend(&mut self, segment: &Segment) {
+        self.next_seq = self.next_seq.max(segment.end_seq());
+        if segment.active {
+            self.active_first_seq = Some(segment.first_seq);
+        }
+    }
 }
 
 impl Persistence {
-    pub fn new() -> io::Result<Self> {
-        let wal_file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(WAL_FILE)?;
-        let wal_writer = BufWriter::new(wal_file);
+    /// Opens the WAL in `data_dir` for appending, keeping the directory
+    /// locked until this is dropped. Sequence numbers carry on from the
+    /// snapshot and the WAL segments already there, and a new active segment
+    /// gets its header first.
+    pub fn open(data_dir: DataDir) -> io::Result<Self> {
+        let mut position = WalPosition::after_snapshots(data_dir.path())?;
+        for path in segment_paths(data_dir.path())? {
+            if let Some(segment) = read_segment(&path)? {
+                position.extend(&segment);
+            }
+        }
+        Self::open_at(data_dir, position)
+    }
 
-        Ok(Persistence { wal_writer })
+    /// Like `open`, for a `data_dir` that `recovery` was just read from, so
+    /// the WAL is not read a second time.
+    pub fn open_recovered(data_dir: DataDir, recovery: &Recovery) -> io::Result<Self> {
+        Self::open_at(data_dir, recovery.position)
+    }
+
+    fn open_at(data_dir: DataDir, position: WalPosition) -> io::Result<Self> {
+        let wal_first_seq = position.active_first_seq.unwrap_or(position.next_seq);
+        let wal_writer = open_segment(&data_dir.path().join(WAL_FILE), wal_first_seq)?;
+
+        Ok(Persistence {
+            data_dir,
+            wal_writer,
+            wal_first_seq,
+            next_seq: position.next_seq,
+            snapshot_seq: position.snapshot_seq,
+            last_snapshot: Instant::now(),
+            last_save_ms: now_ms(),
+            snapshots_kept: DEFAULT_SNAPSHOTS_KEPT,
+            snapshot_started: None,
+            last_outcome: None,
+        })
+    }
+
+    /// Sets how many snapshots to keep for rollback, at least one.
+    pub fn set_snapshots_kept(&mut self, snapshots_kept: usize) {
+        self.snapshots_kept = snapshots_kept.max(1);
     }
 
     pub fn log_entry(&mut self, entry: WalEntry) -> io::Result<()> {
-        let entry_bytes =
-            bincode::serialize(&entry).map_err(|e| io::Error::new(ErrorKind::Other, e))?; // convert to bytes
-        self.wal_writer.write_all(&entry_bytes)?;
-        self.wal_writer.write_all(b"\n")?;
-        self.wal_writer.flush()
-    }
-
-    pub fn load_wal(db: &mut GeoDatabase) -> io::Result<()> {
-        let wal_file = File::open(WAL_FILE)?;
-        let reader = BufReader::new(wal_file);
-
-        for line in reader.lines() {
-            let line = line?;
-            let entry: WalEntry = bincode::deserialize(line.as_bytes())
-                .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
-            match entry {
-                WalEntry::GeoAdd { key, coords } => {
-                    db.geo_add(key, coords);
+        let payload = bincode::serialize(&entry).map_err(io::Error::other)?;
+        let len = u32::try_from(payload.len())
+            .map_err(io::Error::other)?
+            .to_le_bytes();
+        self.wal_writer.write_all(&len)?;
+        self.wal_writer
+            .write_all(&crc32fast::hash(&len).to_le_bytes())?;
+        self.wal_writer
+            .write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        self.wal_writer.write_all(&payload)?;
+        self.wal_writer.flush()?;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// How many entries have been logged since the newest snapshot.
+    pub fn changes_since_snapshot(&self) -> u64 {
+        self.next_seq - 1 - self.snapshot_seq
+    }
+
+    /// Whether any of `rules` calls for a snapshot now. Without changes there
+    /// is nothing new to save, and nothing is due while a snapshot is being
+    /// written or for `SNAPSHOT_RETRY_SECONDS` after one failed.
+    pub fn snapshot_due(&self, rules: &[SnapshotRule]) -> bool {
+        let retrying = self.last_outcome.as_ref().is_some_and(|outcome| {
+            outcome.error.is_some()
+                && outcome.finished.elapsed() < Duration::from_secs(SNAPSHOT_RETRY_SECONDS)
+        });
+        if self.snapshot_started.is_some() || retrying {
+            return false;
+        }
+        let changes = self.changes_since_snapshot();
+        let elapsed = self.last_snapshot.elapsed();
+        changes > 0
+            && rules
+                .iter()
+                .any(|rule| elapsed >= rule.after && changes >= rule.changes)
+    }
+
+    /// Takes a point-in-time copy of `collections`, which must be the state
+    /// after every entry logged so far, and moves the active WAL segment aside
+    /// so new entries start a fresh one. The copy is cheap, so callers hold
+    /// both locks for this part only and write the returned snapshot after
+    /// letting go of them. Fails while another snapshot is being written, as
+    /// an older one could then be counted as newer.
+    pub fn begin_snapshot(&mut self, collections: &GeoCollections) -> io::Result<PendingSnapshot> {
+        if self.snapshot_started.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                SNAPSHOT_IN_PROGRESS,
+            ));
+        }
+        let seq = self.next_seq - 1;
+        if self.wal_first_seq <= seq {
+            self.wal_writer.flush()?;
+            self.wal_writer.get_ref().sync_all()?;
+            let dir = self.data_dir.path();
+            let wal_path = dir.join(WAL_FILE);
+            fs::rename(&wal_path, rotated_segment_path(dir, self.wal_first_seq))?;
+            // Syncs the directory, and with it the rename, too.
+            self.wal_writer = open_segment(&wal_path, self.next_seq)?;
+            self.wal_first_seq = self.next_seq;
+        }
+
+        self.snapshot_started = Some(Instant::now());
+        Ok(PendingSnapshot {
+            dir: self.data_dir.path().to_path_buf(),
+            seq,
+            created_at: now_ms(),
+            collections: collections.clone(),
+            snapshots_kept: self.snapshots_kept,
+        })
+    }
+
+    /// Records how writing `snapshot` went. Another snapshot can begin after
+    /// this.
+    pub fn finish_snapshot(&mut self, snapshot: &PendingSnapshot, result: &io::Result<()>) {
+        let started = self.snapshot_started.take().unwrap_or_else(Instant::now);
+        if result.is_ok() {
+            self.snapshot_seq = self.snapshot_seq.max(snapshot.seq);
+            self.last_snapshot = Instant::now();
+            self.last_save_ms = snapshot.created_at;
+        }
+        self.last_outcome = Some(SnapshotOutcome {
+            finished: Instant::now(),
+            took: started.elapsed(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+
+    /// When the newest snapshot was taken, in Unix seconds, for `LASTSAVE`.
+    pub fn last_save(&self) -> u64 {
+        self.last_save_ms / 1000
+    }
+
+    /// The state of snapshots and the WAL as name and value pairs, for
+    /// `INFO`. Durations are in milliseconds, and -1 when there is none.
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        let millis = |duration: Option<Duration>| {
+            duration.map_or("-1".to_string(), |duration| {
+                duration.as_millis().to_string()
+            })
+        };
+        let status = match &self.last_outcome {
+            None => "none",
+            Some(SnapshotOutcome { error: None, .. }) => "ok",
+            Some(_) => "err",
+        };
+        let mut info = vec![
+            (
+                "changes_since_last_save",
+                self.changes_since_snapshot().to_string(),
+            ),
+            ("last_save_time", self.last_save().to_string()),
+            ("last_snapshot_seq", self.snapshot_seq.to_string()),
+            ("wal_seq", (self.next_seq - 1).to_string()),
+            (
+                "snapshot_in_progress",
+                (self.snapshot_started.is_some() as u8).to_string(),
+            ),
+            (
+                "current_snapshot_ms",
+                millis(self.snapshot_started.map(|started| started.elapsed())),
+            ),
+            ("last_snapshot_status", status.to_string()),
+            (
+                "last_snapshot_ms",
+                millis(self.last_outcome.as_ref().map(|outcome| outcome.took)),
+            ),
+        ];
+        if let Some(error) = self
+            .last_outcome
+            .as_ref()
+            .and_then(|outcome| outcome.error.clone())
+        {
+            info.push(("last_snapshot_error", error));
+        }
+        info
+    }
+
+    /// Loads the newest snapshot in `dir`, if there is one, and replays the
+    /// WAL segments after it: first those moved aside by snapshots, then the
+    /// active one. Records the snapshot already covers are skipped. A snapshot
+    /// that fails its checks is passed over for the one before it, whose WAL
+    /// segments are still there to bring it up to date.
+    ///
+    /// A record cut short or failing its checksum at the very end of the
+    /// active segment is what a crash in the middle of `log_entry` leaves
+    /// behind, so it is truncated away and replay ends there. Anything else
+    /// means the log itself is damaged: a record header failing its own
+    /// checksum, a bad record with more data after it, or a torn record in a
+    /// rotated segment, which was synced whole before it was moved aside.
+    /// Replay then stops with an `InvalidData` error rather than skip over
+    /// it, as it does when entries between the snapshot and the WAL are
+    /// missing or no snapshot can be loaded.
+    ///
+    /// Recovery only reads `dir`, apart from cutting off torn records, and
+    /// does not lock it: callers starting a node hold its `DataDir` first,
+    /// then pass the result to `open_recovered`.
+    pub fn recover(dir: impl AsRef<Path>) -> io::Result<Recovery> {
+        let dir = dir.as_ref();
+        let mut position = WalPosition::after_snapshots(dir)?;
+        let snapshots = snapshots(dir)?;
+        let mut loaded = None;
+        for (_, path) in snapshots.iter().rev() {
+            match read_snapshot(path) {
+                Ok(snapshot) => {
+                    loaded = Some(snapshot);
+                    break;
                 }
+                Err(e) => warn!("Skipping snapshot {}: {}", path.display(), e),
+            }
+        }
+        let (snapshot_seq, mut collections) = match loaded {
+            Some(snapshot) => snapshot,
+            None if snapshots.is_empty() => (0, GeoCollections::new()),
+            None => return Err(invalid_data("no snapshot could be loaded".to_string())),
+        };
+
+        let mut next_seq = snapshot_seq + 1;
+        for path in segment_paths(dir)? {
+            let Some(segment) = read_segment(&path)? else {
+                continue;
+            };
+            if segment.first_seq > next_seq {
+                return Err(invalid_data(format!(
+                    "WAL is missing entries {} to {}",
+                    next_seq,
+                    segment.first_seq - 1
+                )));
+            }
+            position.extend(&segment);
+            for (seq, record) in (segment.first_seq..).zip(&segment.records) {
+                if seq < next_seq {
+                    continue;
+                }
+                let payload = &segment.bytes[record.clone()];
+                let entry: WalEntry = bincode::deserialize(payload).map_err(|e| {
+                    invalid_data(format!(
+                        "undecodable WAL record at offset {} of {}: {}",
+                        record.start - RECORD_HEADER_LEN,
+                        path.display(),
+                        e
+                    ))
+                })?;
+                apply_entry(&mut collections, entry)?;
+                next_seq = seq + 1;
+            }
+        }
+        Ok(Recovery {
+            collections,
+            position,
+        })
+    }
+}
+
+/// A point-in-time copy waiting to be written, see `begin_snapshot`.
+#[derive(Debug)]
+pub struct PendingSnapshot {
+    dir: PathBuf,
+    seq: u64,
+    created_at: u64,
+    collections: GeoCollections,
+    snapshots_kept: usize,
+}
+
+impl PendingSnapshot {
+    /// The last sequence number the snapshot covers.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Serializes the snapshot to a temporary file and renames it into place,
+    /// so a crash never leaves a half written one behind. Once it is durable,
+    /// snapshots beyond the number kept are deleted, along with the WAL
+    /// segments the oldest remaining one covers. This takes no locks and can
+    /// run on a blocking thread while writes go on.
+    pub fn write(&self) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_LEN);
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&self.created_at.to_le_bytes());
+        bytes.extend_from_slice(&self.seq.to_le_bytes());
+        bytes.extend_from_slice(&(self.collections.key_count() as u64).to_le_bytes());
+        bincode::serialize_into(&mut bytes, &self.collections).map_err(io::Error::other)?;
+        let crc = crc32fast::hash(&bytes[16..]);
+        bytes[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        let path = snapshot_path(&self.dir, self.seq);
+        let temp_path = path.with_extension("bincode.tmp");
+        let mut snapshot_file = File::create(&temp_path)?;
+        snapshot_file.write_all(&bytes)?;
+        snapshot_file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        sync_dir(&self.dir)?;
+
+        let snapshots = snapshots(&self.dir)?;
+        let dropped = snapshots.len().saturating_sub(self.snapshots_kept);
+        for (_, path) in &snapshots[..dropped] {
+            fs::remove_file(path)?;
+        }
+
+        // A rotated segment ends where the next one begins. The last one ends
+        // at the latest where this snapshot does.
+        let oldest_seq = snapshots[dropped].0;
+        let segments = rotated_segments(&self.dir)?;
+        for (i, (_, path)) in segments.iter().enumerate() {
+            let end_seq = segments.get(i + 1).map_or(self.seq + 1, |(next, _)| *next);
+            if end_seq <= oldest_seq + 1 {
+                fs::remove_file(path)?;
             }
         }
         Ok(())
     }
+}
+
+/// Snapshots `collections` and writes the snapshot before returning, like
+/// Redis's `SAVE`. Writes wait only while the copy is taken. It blocks on
+/// file I/O, so async callers run it on a blocking thread.
+pub fn save(
+    collections: &Mutex<GeoCollections>,
+    persistence: &Mutex<Persistence>,
+) -> io::Result<u64> {
+    let snapshot = begin(collections, persistence)?;
+    let result = snapshot.write();
+    persistence
+        .lock()
+        .unwrap()
+        .finish_snapshot(&snapshot, &result);
+    result.map(|()| snapshot.seq)
+}
+
+/// Snapshots `collections` like `save`, but writes the snapshot on a blocking
+/// thread and returns as soon as the copy is taken, like Redis's `BGSAVE`.
+/// How it went shows in `Persistence::info`.
+pub fn background_save(
+    collections: &Mutex<GeoCollections>,
+    persistence: &Arc<Mutex<Persistence>>,
+) -> io::Result<()> {
+    let snapshot = begin(collections, persistence)?;
+    let persistence = Arc::clone(persistence);
+    tokio::task::spawn_blocking(move || {
+        let result = snapshot.write();
+        match &result {
+            Ok(()) => info!("Snapshot created up to WAL entry {}.", snapshot.seq),
+            Err(e) => error!("Failed to create snapshot; err = {:?}", e),
+        }
+        persistence
+            .lock()
+            .unwrap()
+            .finish_snapshot(&snapshot, &result);
+    });
+    Ok(())
+}
+
+fn begin(
+    collections: &Mutex<GeoCollections>,
+    persistence: &Mutex<Persistence>,
+) -> io::Result<PendingSnapshot> {
+    let collections = collections.lock().unwrap();
+    persistence.lock().unwrap().begin_snapshot(&collections)
+}
+
+/// A WAL segment read into memory, with the payload ranges of its records.
+struct Segment {
+    /// Whether this is the active segment, the one still being appended to.
+    active: bool,
+    first_seq: u64,
+    bytes: Vec<u8>,
+    records: Vec<Range<usize>>,
+}
+
+impl Segment {
+    /// The sequence number the record after this segment's last one gets.
+    fn end_seq(&self) -> u64 {
+        self.first_seq + self.records.len() as u64
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the WAL segment at `path`, truncating a torn last record if it is
+/// the active segment, see `Persistence::recover`. An active segment whose
+/// header was cut short by a crash right after it was created holds no
+/// records yet; it is emptied, so `open_segment` writes the header again,
+/// and `None` is returned.
+fn read_segment(path: &Path) -> io::Result<Option<Segment>> {
+    let bytes = fs::read(path)?;
+    let active = path.ends_with(WAL_FILE);
+    if active && is_torn_header(&bytes) {
+        warn!(
+            "Truncating torn WAL header: {} bytes of {}",
+            bytes.len(),
+            path.display()
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(0)?;
+        return Ok(None);
+    }
+    let first_seq = check_wal_header(&bytes)?;
+
+    let mut records = Vec::new();
+    let mut offset = WAL_HEADER_LEN;
+    while offset < bytes.len() {
+        let record = &bytes[offset..];
+        let payload = match read_record(record) {
+            RecordRead::Whole(payload) => payload,
+            RecordRead::Torn if active => {
+                warn!(
+                    "Truncating torn WAL record: {} bytes at offset {} of {}",
+                    record.len(),
+                    offset,
+                    path.display()
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(offset as u64)?;
+                break;
+            }
+            RecordRead::Torn => {
+                return Err(invalid_data(format!(
+                    "torn WAL record at offset {} of rotated segment {}",
+                    offset,
+                    path.display()
+                )))
+            }
+            RecordRead::Corrupt => {
+                return Err(invalid_data(format!(
+                    "corrupt WAL record at offset {} of {}",
+                    offset,
+                    path.display()
+                )))
+            }
+        };
+        let start = offset + RECORD_HEADER_LEN;
+        records.push(start..start + payload.len());
+        offset = start + payload.len();
+    }
+
+    Ok(Some(Segment {
+        active,
+        first_seq,
+        bytes,
+        records,
+    }))
+}
+
+/// Whether `bytes` is the start of a WAL header that was never finished.
+fn is_torn_header(bytes: &[u8]) -> bool {
+    let version = WAL_VERSION.to_le_bytes();
+    let expected = WAL_MAGIC.iter().chain(&version);
+    bytes.len() < WAL_HEADER_LEN && expected.zip(bytes).all(|(a, b)| a == b)
+}
+
+/// Checks the header of a WAL segment and returns its first sequence number.
+fn check_wal_header(bytes: &[u8]) -> io::Result<u64> {
+    if bytes.len() < 12 || &bytes[..8] != WAL_MAGIC {
+        return Err(invalid_data("not a WAL file".to_string()));
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != WAL_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported WAL format version {}",
+            version
+        )));
+    }
+    let first_seq = bytes
+        .get(12..WAL_HEADER_LEN)
+        .ok_or_else(|| invalid_data("WAL header cut short".to_string()))?;
+    Ok(u64::from_le_bytes(first_seq.try_into().unwrap()))
+}
+
+/// Opens the WAL segment at `path` for appending, writing its header if the
+/// file is new or empty. A new header is synced along with the directory
+/// entry, so records are never appended to a segment that could come back
+/// without one.
+fn open_segment(path: &Path, first_seq: u64) -> io::Result<BufWriter<File>> {
+    let wal_file = OpenOptions::new().append(true).create(true).open(path)?;
+    let empty = wal_file.metadata()?.len() == 0;
+    let mut wal_writer = BufWriter::new(wal_file);
+    if empty {
+        wal_writer.write_all(WAL_MAGIC)?;
+        wal_writer.write_all(&WAL_VERSION.to_le_bytes())?;
+        wal_writer.write_all(&first_seq.to_le_bytes())?;
+        wal_writer.flush()?;
+        wal_writer.get_ref().sync_all()?;
+        if let Some(dir) = path.parent() {
+            sync_dir(dir)?;
+        }
+    }
+    Ok(wal_writer)
+}
+
+fn rotated_segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{}.{}", WAL_FILE, first_seq))
+}
+
+/// The files in `dir` named `prefix`, a number and `suffix`, by number.
+fn numbered_files(dir: &Path, prefix: &str, suffix: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_suffix(suffix))
+            .and_then(|number| number.parse::<u64>().ok());
+        if let Some(number) = number {
+            files.push((number, entry.path()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The segments moved aside by `begin_snapshot` in `dir`, oldest first.
+fn rotated_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    numbered_files(dir, &format!("{}.", WAL_FILE), "")
+}
+
+/// Every WAL segment in `dir` in replay order: the rotated ones, then the
+/// active one if it exists.
+fn segment_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = rotated_segments(dir)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+    let wal_path = dir.join(WAL_FILE);
+    if wal_path.exists() {
+        paths.push(wal_path);
+    }
+    Ok(paths)
+}
+
+fn snapshot_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{}{}{}", SNAPSHOT_PREFIX, seq, SNAPSHOT_SUFFIX))
+}
+
+/// The snapshots in `dir` by the last sequence number they cover, oldest
+/// first.
+fn snapshots(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    numbered_files(dir, SNAPSHOT_PREFIX, SNAPSHOT_SUFFIX)
+}
 
-    pub fn create_snapshot(db: &GeoDatabase) -> io::Result<()> {
-        let snapshot_file = File::create(SNAPSHOT_FILE)?;
-        let writer = BufWriter::new(snapshot_file);
-        bincode::serialize_into(writer, db).map_err(|e| io::Error::new(ErrorKind::Other, e))
+/// Reads the snapshot at `path`, checking its header, checksum and key
+/// count, and returns the last sequence number it covers with the
+/// collections.
+fn read_snapshot(path: &Path) -> io::Result<(u64, GeoCollections)> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < SNAPSHOT_HEADER_LEN || &bytes[..8] != SNAPSHOT_MAGIC {
+        return Err(invalid_data("not a snapshot file".to_string()));
     }
+    let field = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported snapshot format version {}",
+            version
+        )));
+    }
+    let crc = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+    if crc32fast::hash(&bytes[16..]) != crc {
+        return Err(invalid_data("snapshot checksum mismatch".to_string()));
+    }
+    let (seq, key_count) = (field(24), field(32));
+    let collections: GeoCollections = bincode::deserialize(&bytes[SNAPSHOT_HEADER_LEN..])
+        .map_err(|e| invalid_data(format!("undecodable snapshot: {}", e)))?;
+    if collections.key_count() as u64 != key_count {
+        return Err(invalid_data(format!(
+            "snapshot has {} keys, its header says {}",
+            collections.key_count(),
+            key_count
+        )));
+    }
+    Ok((seq, collections))
+}
+
+/// Makes renames and removals in `dir` durable.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
 
-    pub fn load_snapshot() -> io::Result<GeoDatabase> {
-        let snapshot_file = File::open(SNAPSHOT_FILE)?;
-        let reader = BufReader::new(snapshot_file);
-        let db: GeoDatabase =
-            bincode::deserialize_from(reader).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
-        Ok(db)
+/// What `read_record` found at the start of the rest of a segment.
+enum RecordRead<'a> {
+    /// A whole record, with its payload.
+    Whole(&'a [u8]),
+    /// A record cut short, or whose payload fails its checksum with nothing
+    /// after it, as a crash part way through `log_entry` leaves it.
+    Torn,
+    /// A record whose header fails its checksum, so its length cannot be
+    /// trusted, or whose payload fails its checksum with more data after it.
+    Corrupt,
+}
+
+/// Reads the record at the start of `record`, which runs to the end of the
+/// segment.
+fn read_record(record: &[u8]) -> RecordRead<'_> {
+    let Some(header) = record.get(..RECORD_HEADER_LEN) else {
+        return RecordRead::Torn;
+    };
+    let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    if crc32fast::hash(&header[..4]) != field(4) {
+        return RecordRead::Corrupt;
+    }
+    let end = RECORD_HEADER_LEN + field(0) as usize;
+    match record.get(RECORD_HEADER_LEN..end) {
+        Some(payload) if crc32fast::hash(payload) == field(8) => RecordRead::Whole(payload),
+        Some(_) if end < record.len() => RecordRead::Corrupt,
+        _ => RecordRead::Torn,
+    }
+}
+
+/// Applies one logged write through the `restore_*` setters, which never ask
+/// the clock whether a key is live: a record does what it did when written.
+fn apply_entry(collections: &mut GeoCollections, entry: WalEntry) -> io::Result<()> {
+    match entry {
+        WalEntry::CreateCollection { collection } => {
+            collections.create_collection(&collection);
+        }
+        WalEntry::DropCollection { collection } => {
+            collections.drop_collection(&collection);
+        }
+        WalEntry::GeoAdd {
+            collection,
+            key,
+            geometry,
+            properties,
+            expires_at,
+        } => {
+            collections.create_collection(&collection);
+            let db = collections.get_mut(&collection).map_err(io::Error::other)?;
+            db.restore_geometry(key.clone(), geometry)
+                .map_err(io::Error::other)?;
+            if let Some(properties) = properties {
+                db.restore_props(&key, properties);
+            }
+            if let Some(at) = expires_at {
+                db.restore_expiry(&key, at);
+            }
+        }
+        WalEntry::GeoSetProps {
+            collection,
+            key,
+            properties,
+        } => {
+            if let Ok(db) = collections.get_mut(&collection) {
+                db.restore_props(&key, properties);
+            }
+        }
+        WalEntry::GeoDel { collection, keys } => {
+            if let Ok(db) = collections.get_mut(&collection) {
+                for key in keys {
+                    db.geo_del(&key);
+                }
+            }
+        }
+        WalEntry::GeoExpire {
+            collection,
+            key,
+            at,
+        } => {
+            if let Ok(db) = collections.get_mut(&collection) {
+                db.restore_expiry(&key, at);
+            }
+        }
+        WalEntry::CreateIndex { collection, field } => {
+            if let Ok(db) = collections.get_mut(&collection) {
+                db.create_index(&field);
+            }
+        }
+        WalEntry::DropIndex { collection, field } => {
+            if let Ok(db) = collections.get_mut(&collection) {
+                db.drop_index(&field);
+            }
+        }
+        WalEntry::CreateH3Index {
+            collection,
+            resolution,
+        } => {
+            if let Ok(db) = collections.get_mut(&collection) {
+                db.create_h3_index(resolution);
+            }
+        }
+        WalEntry::DropH3Index { collection } => {
+            if let Ok(db) = collections.get_mut(&collection) {
+                db.drop_h3_index();
+            }
+        }
     }
+    Ok(())
 }
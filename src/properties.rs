@@ -0,0 +1,273 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::str::FromStr;
+
+/// The property map stored alongside a key's geometry, e.g. a name, a type or
+/// a speed.
+pub type Properties = BTreeMap<String, PropValue>;
+
+/// A JSON value as stored in `Properties`.
+///
+/// This mirrors `serde_json::Value`, which cannot go through bincode (it only
+/// deserializes self-describing formats), so it can be written to the WAL and
+/// snapshots. Clients always see plain JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PropValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<PropValue>),
+    Object(Properties),
+}
+
+impl From<Value> for PropValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => PropValue::Null,
+            Value::Bool(b) => PropValue::Bool(b),
+            Value::Number(n) => n.as_f64().map_or(PropValue::Null, PropValue::Number),
+            Value::String(s) => PropValue::String(s),
+            Value::Array(values) => PropValue::Array(values.into_iter().map(Into::into).collect()),
+            Value::Object(map) => PropValue::Object(properties_from_map(map)),
+        }
+    }
+}
+
+impl From<&PropValue> for Value {
+    fn from(value: &PropValue) -> Self {
+        match value {
+            PropValue::Null => Value::Null,
+            PropValue::Bool(b) => Value::Bool(*b),
+            // Whole numbers go back out as JSON integers, the way they came in.
+            PropValue::Number(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(53) => {
+                Value::from(*n as i64)
+            }
+            PropValue::Number(n) => Number::from_f64(*n).map_or(Value::Null, Value::Number),
+            PropValue::String(s) => Value::String(s.clone()),
+            PropValue::Array(values) => Value::Array(values.iter().map(Into::into).collect()),
+            PropValue::Object(properties) => properties_to_json(properties),
+        }
+    }
+}
+
+/// Parses a property map from JSON text. Only a JSON object is accepted.
+pub fn parse_properties(json: &str) -> Option<Properties> {
+    match serde_json::from_str(json).ok()? {
+        Value::Object(map) => Some(properties_from_map(map)),
+        _ => None,
+    }
+}
+
+/// Converts a JSON object, e.g. from a REST body, into a property map.
+pub fn properties_from_map(map: Map<String, Value>) -> Properties {
+    map.into_iter()
+        .map(|(name, value)| (name, value.into()))
+        .collect()
+}
+
+pub fn properties_to_json(properties: &Properties) -> Value {
+    Value::Object(
+        properties
+            .iter()
+            .map(|(name, value)| (name.clone(), value.into()))
+            .collect(),
+    )
+}
+
+/// How a `Filter` compares a property with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompareOp {
+    #[serde(rename = "=")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+}
+
+impl FromStr for CompareOp {
+    type Err = ();
+
+    fn from_str(op: &str) -> Result<Self, Self::Err> {
+        match op {
+            "=" | "==" => Ok(CompareOp::Eq),
+            "!=" => Ok(CompareOp::Ne),
+            "<" => Ok(CompareOp::Lt),
+            "<=" => Ok(CompareOp::Le),
+            ">" => Ok(CompareOp::Gt),
+            ">=" => Ok(CompareOp::Ge),
+            _ => Err(()),
+        }
+    }
+}
+
+impl CompareOp {
+    fn accepts(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering.is_eq(),
+            CompareOp::Ne => ordering.is_ne(),
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Le => ordering.is_le(),
+            CompareOp::Gt => ordering.is_gt(),
+            CompareOp::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+/// A condition on one top-level property, e.g. `rating >= 4`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub field: String,
+    pub op: CompareOp,
+    pub value: PropValue,
+}
+
+impl Filter {
+    /// Whether `properties` satisfy the filter. A missing property, or one of
+    /// a different type than the filter value, never matches.
+    pub fn matches(&self, properties: Option<&Properties>) -> bool {
+        let Some(actual) = properties.and_then(|properties| properties.get(&self.field)) else {
+            return false;
+        };
+        let ordering = match (actual, &self.value) {
+            (PropValue::Number(a), PropValue::Number(b)) => a.partial_cmp(b),
+            (PropValue::String(a), PropValue::String(b)) => Some(a.cmp(b)),
+            (PropValue::Bool(a), PropValue::Bool(b)) => Some(a.cmp(b)),
+            (PropValue::Null, PropValue::Null) => Some(Ordering::Equal),
+            _ => None,
+        };
+        ordering.is_some_and(|ordering| self.op.accepts(ordering))
+    }
+}
+
+/// A scalar property value as a key of a `PropertyIndex`. Keys of different
+/// types never compare equal, and a range lookup stays within one type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum IndexKey {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl IndexKey {
+    /// The key for `value`, or `None` for values that are not indexed
+    /// (null, arrays and objects).
+    pub fn from_value(value: &PropValue) -> Option<Self> {
+        match value {
+            PropValue::Bool(b) => Some(IndexKey::Bool(*b)),
+            PropValue::Number(n) if !n.is_nan() => Some(IndexKey::Number(*n)),
+            PropValue::String(s) => Some(IndexKey::String(s.clone())),
+            _ => None,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexKey::Bool(_) => 0,
+            IndexKey::Number(_) => 1,
+            IndexKey::String(_) => 2,
+        }
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexKey::Bool(a), IndexKey::Bool(b)) => a.cmp(b),
+            (IndexKey::Number(a), IndexKey::Number(b)) => a.total_cmp(b),
+            (IndexKey::String(a), IndexKey::String(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for IndexKey {}
+
+/// A secondary index on one top-level property: the keys holding each value,
+/// sorted by value so that both equality and range filters can use it.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PropertyIndex {
+    entries: BTreeMap<IndexKey, BTreeSet<String>>,
+}
+
+impl PropertyIndex {
+    pub fn insert(&mut self, value: &PropValue, key: &str) {
+        if let Some(index_key) = IndexKey::from_value(value) {
+            self.entries
+                .entry(index_key)
+                .or_default()
+                .insert(key.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, value: &PropValue, key: &str) {
+        let Some(index_key) = IndexKey::from_value(value) else {
+            return;
+        };
+        if let Some(keys) = self.entries.get_mut(&index_key) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.entries.remove(&index_key);
+            }
+        }
+    }
+
+    /// The keys whose value satisfies `op value`, or `None` when the index
+    /// cannot answer the filter (`!=`, or a value that is not indexed).
+    pub fn lookup(&self, op: CompareOp, value: &PropValue) -> Option<Vec<&String>> {
+        let bound = IndexKey::from_value(value)?;
+        let same_type = |(key, _): &(&IndexKey, _)| key.rank() == bound.rank();
+        let sets: Vec<&BTreeSet<String>> = match op {
+            CompareOp::Ne => return None,
+            CompareOp::Eq => self.entries.get(&bound).into_iter().collect(),
+            CompareOp::Lt | CompareOp::Le => {
+                let upper = if op == CompareOp::Lt {
+                    Bound::Excluded(&bound)
+                } else {
+                    Bound::Included(&bound)
+                };
+                self.entries
+                    .range((Bound::Unbounded, upper))
+                    .rev()
+                    .take_while(same_type)
+                    .map(|(_, keys)| keys)
+                    .collect()
+            }
+            CompareOp::Gt | CompareOp::Ge => {
+                let lower = if op == CompareOp::Gt {
+                    Bound::Excluded(&bound)
+                } else {
+                    Bound::Included(&bound)
+                };
+                self.entries
+                    .range((lower, Bound::Unbounded))
+                    .take_while(same_type)
+                    .map(|(_, keys)| keys)
+                    .collect()
+            }
+        };
+        Some(sets.into_iter().flatten().collect())
+    }
+}
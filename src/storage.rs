@@ -1,87 +1,1121 @@
-use geo::{HaversineDistance, Point, Polygon};
-use rstar::{PointDistance, RTree, RTreeObject, AABB};
+use crate::geospatial::{
+    box_envelopes, envelope_reach_2, geometry_distance, geometry_to_wkt, haversine_distance_to,
+    radius_envelopes, AxisOrder, DistanceMethod,
+};
+use crate::h3::{geometry_cell, polyfill, H3Index, PolyfillError};
+use crate::properties::{Filter, Properties, PropertyIndex};
+use geo::line_intersection::LineIntersection;
+use geo::sweep::Intersections;
+use geo::vincenty_distance::FailedToConvergeError;
+use geo::{
+    BoundingRect, Contains, Coord, CoordsIter, Geometry, HaversineDistance, Intersects, Line,
+    LineString, MultiPolygon, Point, Polygon, Rect, Relate, Within,
+};
+use h3o::{CellIndex, Resolution};
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{Envelope, PointDistance, RTree, RTreeObject, AABB};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Restricts a `geo_add` to keys that do (`IfExists`, Redis `XX`) or do not
+/// (`IfNotExists`, Redis `NX`) already exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddCondition {
+    #[serde(rename = "NX")]
+    IfNotExists,
+    #[serde(rename = "XX")]
+    IfExists,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum AddOutcome {
+    Created,
+    Updated,
+    Skipped,
+}
+
+/// How a stored geometry has to relate to a GEOSEARCHBOX box to match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum BoxMatch {
+    /// Any part of the geometry lies inside the box.
+    #[default]
+    Intersects,
+    /// The whole geometry lies inside the box.
+    Within,
+}
+
+/// How a stored geometry has to relate to a query polygon to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum AreaMatch {
+    /// The stored geometry lies inside the query polygon.
+    Within,
+    /// The stored geometry and the query polygon share at least one point.
+    Intersects,
+    /// The stored geometry contains the whole query polygon.
+    Contains,
+}
+
+/// Why a geometry was rejected before reaching the database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeometryError {
+    /// The input is not WKT, or not one of the WKT types we store.
+    InvalidWkt,
+    /// A geohash that is empty, too long or has a character outside the
+    /// geohash alphabet.
+    InvalidGeohash(String),
+    /// A geometry type `GeoDatabase` does not store (e.g. a collection).
+    UnsupportedType,
+    /// The geometry has no coordinates at all.
+    Empty,
+    /// A coordinate is NaN or infinite.
+    NotFinite,
+    LatitudeOutOfRange(f64),
+    LongitudeOutOfRange(f64),
+    /// A line string with fewer than two distinct points.
+    LineTooShort,
+    /// A polygon ring with fewer than three distinct points.
+    RingTooShort,
+    /// A polygon ring whose last point does not repeat its first.
+    UnclosedRing,
+    /// Polygon edges that cross or touch anywhere but at a shared vertex of
+    /// consecutive edges.
+    SelfIntersection,
+    /// A polygon hole that is not inside the exterior ring.
+    HoleOutsideShell,
+    /// A polygon hole inside another hole of the same polygon.
+    NestedHole,
+    /// Multipolygon parts whose interiors overlap. Parts may still touch.
+    OverlappingParts,
+    /// A search box whose minimum latitude is above its maximum.
+    InvertedBox {
+        min_lat: f64,
+        max_lat: f64,
+    },
+}
+
+impl fmt::Display for GeometryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeometryError::InvalidWkt => write!(f, "invalid WKT"),
+            GeometryError::InvalidGeohash(hash) => write!(f, "invalid geohash '{}'", hash),
+            GeometryError::UnsupportedType => write!(f, "unsupported geometry type"),
+            GeometryError::Empty => write!(f, "geometry has no coordinates"),
+            GeometryError::NotFinite => write!(f, "coordinates must be finite numbers"),
+            GeometryError::LatitudeOutOfRange(lat) => {
+                write!(f, "latitude {} is outside [-90, 90]", lat)
+            }
+            GeometryError::LongitudeOutOfRange(lon) => {
+                write!(f, "longitude {} is outside [-180, 180]", lon)
+            }
+            GeometryError::LineTooShort => {
+                write!(f, "a line string needs at least 2 distinct points")
+            }
+            GeometryError::RingTooShort => {
+                write!(f, "a polygon ring needs at least 3 distinct points")
+            }
+            GeometryError::UnclosedRing => {
+                write!(
+                    f,
+                    "polygon ring is not closed: the last point must repeat the first"
+                )
+            }
+            GeometryError::SelfIntersection => write!(f, "polygon is self-intersecting"),
+            GeometryError::HoleOutsideShell => {
+                write!(f, "polygon hole lies outside its exterior ring")
+            }
+            GeometryError::NestedHole => write!(f, "polygon hole lies inside another hole"),
+            GeometryError::OverlappingParts => write!(f, "multipolygon parts overlap"),
+            GeometryError::InvertedBox { min_lat, max_lat } => {
+                write!(
+                    f,
+                    "box minimum latitude {} is above its maximum {}",
+                    min_lat, max_lat
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GeometryError {}
+
+/// Checks that `geometry` can be stored: every coordinate is a finite
+/// (x = lon, y = lat) pair in range, lines have two distinct points, and
+/// polygons pass `validate_polygon`.
+pub fn validate_geometry(geometry: &Geometry<f64>) -> Result<(), GeometryError> {
+    validate_coords(geometry)?;
+    match geometry {
+        Geometry::Point(_) | Geometry::MultiPoint(_) => Ok(()),
+        Geometry::LineString(line) => validate_line_string(line),
+        Geometry::MultiLineString(lines) => lines.iter().try_for_each(validate_line_string),
+        Geometry::Polygon(polygon) => validate_rings(polygon),
+        Geometry::MultiPolygon(polygons) => validate_parts(polygons),
+        _ => Err(GeometryError::UnsupportedType),
+    }
+}
+
+/// Checks that `polygon` is a valid, simple polygon: coordinates in range,
+/// closed rings of at least three distinct points, no crossing or touching
+/// edges, and holes inside the exterior ring but not inside each other.
+pub fn validate_polygon(polygon: &Polygon<f64>) -> Result<(), GeometryError> {
+    validate_coords(polygon)?;
+    validate_rings(polygon)
+}
+
+fn validate_coords(geometry: &impl CoordsIter<Scalar = f64>) -> Result<(), GeometryError> {
+    let mut coords = geometry.coords_iter().peekable();
+    if coords.peek().is_none() {
+        return Err(GeometryError::Empty);
+    }
+    coords.try_for_each(|coord| validate_lat_lon(coord.y, coord.x))
+}
+
+/// Checks that a query or stored coordinate is finite and in range.
+pub fn validate_lat_lon(lat: f64, lon: f64) -> Result<(), GeometryError> {
+    if !lat.is_finite() || !lon.is_finite() {
+        Err(GeometryError::NotFinite)
+    } else if !(-90.0..=90.0).contains(&lat) {
+        Err(GeometryError::LatitudeOutOfRange(lat))
+    } else if !(-180.0..=180.0).contains(&lon) {
+        Err(GeometryError::LongitudeOutOfRange(lon))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that both corners of a search box are in range and that its
+/// latitudes are in order. Longitudes may be in either order: a box with
+/// `min_lon > max_lon` wraps across the antimeridian.
+pub fn validate_box(
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+) -> Result<(), GeometryError> {
+    validate_lat_lon(min_lat, min_lon)?;
+    validate_lat_lon(max_lat, max_lon)?;
+    if min_lat > max_lat {
+        return Err(GeometryError::InvertedBox { min_lat, max_lat });
+    }
+    Ok(())
+}
+
+fn validate_line_string(line: &LineString<f64>) -> Result<(), GeometryError> {
+    if distinct_points(line) < 2 {
+        return Err(GeometryError::LineTooShort);
+    }
+    Ok(())
+}
+
+fn validate_rings(polygon: &Polygon<f64>) -> Result<(), GeometryError> {
+    for ring in std::iter::once(polygon.exterior()).chain(polygon.interiors()) {
+        if !ring.is_closed() {
+            return Err(GeometryError::UnclosedRing);
+        }
+        // The closing point repeats the first, so a triangle has four.
+        if distinct_points(ring) < 4 {
+            return Err(GeometryError::RingTooShort);
+        }
+    }
+
+    let edges = std::iter::once(polygon.exterior())
+        .chain(polygon.interiors())
+        .flat_map(|ring| ring.lines())
+        .filter(|edge| edge.start != edge.end);
+    if Intersections::from_iter(edges)
+        .any(|(a, b, intersection)| !meets_at_shared_vertex(&a, &b, &intersection))
+    {
+        return Err(GeometryError::SelfIntersection);
+    }
+
+    // With no edges crossing, a hole is inside the shell iff any vertex is.
+    let shell = Polygon::new(polygon.exterior().clone(), vec![]);
+    if polygon
+        .interiors()
+        .iter()
+        .any(|hole| !shell.contains(&hole.0[0]))
+    {
+        return Err(GeometryError::HoleOutsideShell);
+    }
+
+    // Nor do the holes cross each other, so the same goes for nesting.
+    let holes: Vec<Polygon<f64>> = polygon
+        .interiors()
+        .iter()
+        .map(|hole| Polygon::new(hole.clone(), vec![]))
+        .collect();
+    for (i, hole) in polygon.interiors().iter().enumerate() {
+        if holes
+            .iter()
+            .enumerate()
+            .any(|(j, other)| i != j && other.contains(&hole.0[0]))
+        {
+            return Err(GeometryError::NestedHole);
+        }
+    }
+    Ok(())
+}
+
+/// Checks every part of a multipolygon like `validate_rings`, and that no
+/// two parts overlap. Parts may touch along their boundaries.
+fn validate_parts(polygons: &MultiPolygon<f64>) -> Result<(), GeometryError> {
+    polygons.iter().try_for_each(validate_rings)?;
+    for (i, part) in polygons.iter().enumerate() {
+        for other in &polygons.0[i + 1..] {
+            let matrix = part.relate(other);
+            if !matrix.is_disjoint() && !matrix.is_touches() {
+                return Err(GeometryError::OverlappingParts);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Number of points in `line`, not counting immediate repeats.
+fn distinct_points(line: &LineString<f64>) -> usize {
+    let steps = line.0.windows(2).filter(|pair| pair[0] != pair[1]).count();
+    steps + usize::from(!line.0.is_empty())
+}
+
+/// Whether two edges only meet where one ends and the other starts, as
+/// consecutive edges of a ring do.
+fn meets_at_shared_vertex(
+    a: &Line<f64>,
+    b: &Line<f64>,
+    intersection: &LineIntersection<f64>,
+) -> bool {
+    let shared: Option<Coord<f64>> = if a.end == b.start {
+        Some(a.end)
+    } else if a.start == b.end {
+        Some(a.start)
+    } else {
+        None
+    };
+    matches!(
+        intersection,
+        LineIntersection::SinglePoint { intersection, is_proper: false }
+            if Some(*intersection) == shared
+    )
+}
+
+/// Which side a filtered search starts from, as chosen by
+/// `GeoDatabase::plan_search`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryPlan {
+    /// Run the spatial search and check the filters on every hit.
+    Spatial,
+    /// Look up the filter on `field` in its secondary index, then check the
+    /// other filters and the distance on those keys only.
+    Index { field: String },
+}
+
+/// The distance order of search results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortOrder {
+    /// Nearest first.
+    #[serde(rename = "ASC")]
+    Asc,
+    /// Farthest first.
+    #[serde(rename = "DESC")]
+    Desc,
+}
+
+/// How `GeoDatabase::geo_search_hits` orders and limits its results, after
+/// Redis `GEOSEARCH`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Sort by distance. Unsorted results come in whatever order the index
+    /// yields them.
+    pub order: Option<SortOrder>,
+    /// Return at most this many hits. Without `any` these are the closest
+    /// ones (the farthest ones with `SortOrder::Desc`), which means every
+    /// match has to be found first.
+    pub count: Option<usize>,
+    /// Stop at the first `count` matches found, whichever they are.
+    pub any: bool,
+}
+
+/// The time to live of a key, as reported by `GeoDatabase::geo_ttl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTtl {
+    /// There is no live key with that name.
+    Missing,
+    /// The key never expires.
+    Persistent,
+    /// The key expires after this long.
+    ExpiresIn(Duration),
+}
+
+/// The current time in milliseconds since the Unix epoch, the unit expiry
+/// deadlines are stored and logged in.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// The reply to an expiry too far out to be stored, or an `EX 0`.
+pub const INVALID_EXPIRE_TIME: &str = "invalid expire time";
+
+/// The deadline `seconds` from now, in Unix milliseconds, or `None` if it
+/// does not fit in a `u64`.
+pub fn expiry_deadline(seconds: u64) -> Option<u64> {
+    seconds
+        .checked_mul(1000)
+        .and_then(|ms| now_ms().checked_add(ms))
+}
+
+/// A key matched by a query, with its geodesic distance in metres from the
+/// query centre.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub key: String,
+    pub distance: f64,
+}
+
+/// A point in the R-tree, tagged with the key it is stored under.
+type PointEntry = GeomWithData<Point<f64>, String>;
+/// The bounding box of any non-point geometry in the R-tree, tagged with the
+/// key it is stored under. The geometry itself is only kept in
+/// `GeoDatabase::geometries`.
+type ShapeEntry = GeomWithData<Rectangle<Point<f64>>, String>;
+
+/// Stores one geometry per key. Points are indexed by position in
+/// `point_tree`; lines, polygons and multi-geometries by bounding box in
+/// `shape_tree`.
+///
+/// Every coordinate is stored with x = longitude and y = latitude; callers
+/// convert from the order their clients use (see `AxisOrder`) on the way in.
+///
+/// A key can also carry a property map, kept in `properties` until the key is
+/// deleted. Replacing a key's geometry leaves its properties alone. Selected
+/// top-level properties have a secondary index in `indexes`, which filtered
+/// searches can start from.
+///
+/// Keys with a time to live have their deadline, in Unix milliseconds, in
+/// `expirations`. Once it passes, every read treats the key as gone; the key
+/// is actually removed by `purge_expired` or by the next write to it.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct GeoDatabase {
-    points: HashMap<String, Point<f64>>,
-    polygons: HashMap<String, Polygon<f64>>,
-    point_tree: RTree<Point<f64>>,
-    polygon_tree: RTree<Polygon<f64>>,
+    geometries: HashMap<String, Geometry<f64>>,
+    properties: HashMap<String, Properties>,
+    indexes: HashMap<String, PropertyIndex>,
+    expirations: HashMap<String, u64>,
+    point_tree: RTree<PointEntry>,
+    shape_tree: RTree<ShapeEntry>,
+    h3: Option<H3Index>,
 }
 
 impl GeoDatabase {
     pub fn new() -> Self {
-        GeoDatabase {
-            points: HashMap::new(),
-            polygons: HashMap::new(),
-            point_tree: RTree::new(),
-            polygon_tree: RTree::new(),
+        Self::default()
+    }
+
+    /// How many keys are stored, counting expired ones the sweep has not
+    /// removed yet.
+    pub fn key_count(&self) -> usize {
+        self.geometries.len()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.geometries.contains_key(key) && self.is_live(key, now_ms())
+    }
+
+    /// Whether `key` is stored at all, counting an expired key the sweep has
+    /// not removed yet.
+    pub fn holds_key(&self, key: &str) -> bool {
+        self.geometries.contains_key(key)
+    }
+
+    /// Inserts or replaces the geometry stored under `key`, after checking it
+    /// with `validate_geometry`. Any previous geometry is dropped from the
+    /// R-trees first, so a key never lingers in the index or in both trees
+    /// after its type changes. Like a Redis `SET`, this clears any expiry; an
+    /// already expired key is replaced as if it did not exist.
+    pub fn geo_add(
+        &mut self,
+        key: String,
+        geometry: Geometry<f64>,
+    ) -> Result<AddOutcome, GeometryError> {
+        validate_geometry(&geometry)?;
+        if !self.is_live(&key, now_ms()) {
+            self.geo_del(&key);
         }
+        Ok(self.store_geometry(key, geometry))
     }
 
-    pub fn geo_add(&mut self, key: String, coords: Vec<(f64, f64)>) {
-        if coords.len() == 1 {
-            let point = Point::new(coords[0].1, coords[0].0); // (lon, lat)
-            self.point_tree.insert(point);
-            self.points.insert(key, point);
-        } else {
-            let polygon = Polygon::new(coords.into(), vec![]);
-            self.polygon_tree.insert(polygon.clone());
-            self.polygons.insert(key, polygon);
+    /// Like `geo_add`, but leaves the database untouched and returns
+    /// `AddOutcome::Skipped` when `condition` does not hold.
+    pub fn geo_add_if(
+        &mut self,
+        key: String,
+        geometry: Geometry<f64>,
+        condition: Option<AddCondition>,
+    ) -> Result<AddOutcome, GeometryError> {
+        validate_geometry(&geometry)?;
+        match (condition, self.contains_key(&key)) {
+            (Some(AddCondition::IfNotExists), true) | (Some(AddCondition::IfExists), false) => {
+                Ok(AddOutcome::Skipped)
+            }
+            _ => self.geo_add(key, geometry),
         }
     }
 
     pub fn geo_search(&self, lat: f64, lon: f64, radius: f64) -> Vec<String> {
-        let center = Point::new(lon, lat);
+        self.geo_search_where(lat, lon, radius, &[])
+    }
+
+    /// Returns the keys whose geometry intersects, or with `BoxMatch::Within`
+    /// lies entirely inside, the given box. A box with `min_lon > max_lon`
+    /// wraps across the antimeridian. Fails if the box does not pass
+    /// `validate_box`.
+    pub fn geo_search_box(
+        &self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+        mode: BoxMatch,
+    ) -> Result<Vec<String>, GeometryError> {
+        validate_box(min_lat, min_lon, max_lat, max_lon)?;
         let mut results = Vec::new();
+        let envelopes = box_envelopes(min_lat, min_lon, max_lat, max_lon);
+        let now = now_ms();
 
-        // Search for points within the radius
-        for point in self
-            .point_tree
-            .nearest_neighbor_iter(&center)
-            .filter(|p| p.haversine_distance(&center) <= radius)
-        {
-            if let Some((key, _)) = self.points.iter().find(|(_, &v)| v == *point) {
-                results.push(key.clone());
+        for envelope in &envelopes {
+            for point in self.point_tree.locate_in_envelope(envelope) {
+                results.push(point.data.clone());
             }
         }
 
-        // Create an AABB for the search radius
-        let search_aabb = AABB::from_corners(
-            Point::new(lon - radius, lat - radius),
-            Point::new(lon + radius, lat + radius),
-        );
+        let mut candidates = HashSet::new();
+        for envelope in &envelopes {
+            match mode {
+                BoxMatch::Within => {
+                    for shape in self.shape_tree.locate_in_envelope(envelope) {
+                        candidates.insert(&shape.data);
+                    }
+                }
+                BoxMatch::Intersects => {
+                    let rect = Rect::new(envelope.lower(), envelope.upper());
+                    for shape in self.shape_tree.locate_in_envelope_intersecting(envelope) {
+                        if self.geometries[&shape.data].intersects(&rect) {
+                            candidates.insert(&shape.data);
+                        }
+                    }
+                }
+            }
+        }
+        results.extend(candidates.into_iter().cloned());
+        results.retain(|key| self.is_live(key, now));
 
-        // Search for polygons that intersect with the radius circle
-        for polygon in self
-            .polygon_tree
-            .locate_in_envelope_intersecting(&search_aabb)
-        {
-            if let Some((key, _)) = self.polygons.iter().find(|(_, v)| v.clone() == polygon) {
-                results.push(key.clone());
+        Ok(results)
+    }
+
+    /// Returns the keys whose geometry is within, intersects or contains `area`.
+    /// Candidates come from the R-trees using `area`'s bounding box and are then
+    /// checked with the exact predicate.
+    pub fn geo_search_area(&self, area: &Polygon<f64>, mode: AreaMatch) -> Vec<String> {
+        let now = now_ms();
+        let mut results = Vec::new();
+        let Some(bounds) = area.bounding_rect() else {
+            return results;
+        };
+        let envelope = AABB::from_corners(bounds.min().into(), bounds.max().into());
+
+        // A point cannot contain a polygon, so points only match the other modes.
+        if mode != AreaMatch::Contains {
+            for point in self.point_tree.locate_in_envelope(&envelope) {
+                let matches = match mode {
+                    AreaMatch::Within => point.geom().is_within(area),
+                    _ => area.intersects(point.geom()),
+                };
+                if matches {
+                    results.push(point.data.clone());
+                }
+            }
+        }
+
+        for shape in self.shape_tree.locate_in_envelope_intersecting(&envelope) {
+            let stored = &self.geometries[&shape.data];
+            let matches = match mode {
+                AreaMatch::Within => stored.is_within(area),
+                AreaMatch::Intersects => stored.intersects(area),
+                AreaMatch::Contains => stored.contains(area),
+            };
+            if matches {
+                results.push(shape.data.clone());
             }
         }
+        results.retain(|key| self.is_live(key, now));
 
         results
     }
 
-    pub fn geo_get(&self, key: &str) -> Option<String> {
-        if let Some(point) = self.points.get(key) {
-            Some(format!("POINT({} {})", point.y(), point.x()))
-        } else if let Some(polygon) = self.polygons.get(key) {
-            Some(format!(
-                "POLYGON(({}))",
-                polygon
-                    .exterior()
-                    .points()
-                    .map(|p| format!("{} {}", p.y(), p.x()))
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            ))
-        } else {
-            None
+    /// Returns the keys of every stored polygon or multi-polygon that contains
+    /// (`lat`, `lon`), counting points on the boundary as inside.
+    pub fn geo_containing(&self, lat: f64, lon: f64) -> Vec<String> {
+        let point = Point::new(lon, lat);
+        let now = now_ms();
+        self.shape_tree
+            .locate_all_at_point(&point)
+            .filter(|shape| self.is_live(&shape.data, now))
+            .filter(|shape| match &self.geometries[&shape.data] {
+                Geometry::Polygon(polygon) => polygon.intersects(&point),
+                Geometry::MultiPolygon(polygons) => polygons.intersects(&point),
+                _ => false,
+            })
+            .map(|shape| shape.data.clone())
+            .collect()
+    }
+
+    /// Returns up to `k` keys closest to (`lat`, `lon`), nearest first, by
+    /// haversine distance. Shapes are measured to their closest part, or zero
+    /// when they contain the centre. Hits further than `max_distance` metres
+    /// are left out. An invalid centre matches nothing.
+    pub fn geo_nearest(
+        &self,
+        lat: f64,
+        lon: f64,
+        k: usize,
+        max_distance: Option<f64>,
+    ) -> Vec<SearchHit> {
+        let center = Point::new(lon, lat);
+        let now = now_ms();
+        let mut hits = Vec::new();
+        // rstar's nearest-neighbour search cannot order NaN distances.
+        if k == 0 || validate_lat_lon(lat, lon).is_err() {
+            return hits;
         }
+
+        nearest_in_tree(
+            &self.point_tree,
+            center,
+            k,
+            max_distance,
+            &mut hits,
+            |point| {
+                self.is_live(&point.data, now)
+                    .then(|| point.geom().haversine_distance(&center))
+            },
+        );
+        nearest_in_tree(
+            &self.shape_tree,
+            center,
+            k,
+            max_distance,
+            &mut hits,
+            |shape| {
+                self.is_live(&shape.data, now)
+                    .then(|| haversine_distance_to(&center, &self.geometries[&shape.data]))
+            },
+        );
+        hits
+    }
+
+    /// Returns the geometry stored under `key` as WKT, with coordinates in
+    /// `axis` order.
+    pub fn geo_get(&self, key: &str, axis: AxisOrder) -> Option<String> {
+        self.geometries
+            .get(key)
+            .filter(|_| self.is_live(key, now_ms()))
+            .map(|geometry| geometry_to_wkt(geometry, axis))
+    }
+
+    /// Returns the geometry stored under `key`.
+    pub fn geo_geometry(&self, key: &str) -> Option<&Geometry<f64>> {
+        self.geometries
+            .get(key)
+            .filter(|_| self.is_live(key, now_ms()))
+    }
+
+    /// The distance in metres between the geometries under `key1` and `key2`,
+    /// or `None` if either key does not exist. See `geometry_distance`.
+    pub fn geo_dist(
+        &self,
+        key1: &str,
+        key2: &str,
+        method: DistanceMethod,
+    ) -> Option<Result<f64, FailedToConvergeError>> {
+        let a = self.geo_geometry(key1)?;
+        let b = self.geo_geometry(key2)?;
+        Some(geometry_distance(a, b, method))
+    }
+
+    /// Returns the properties stored under `key`, if it has any.
+    pub fn geo_props(&self, key: &str) -> Option<&Properties> {
+        self.properties
+            .get(key)
+            .filter(|_| self.is_live(key, now_ms()))
+    }
+
+    /// Replaces the properties of an existing key. Returns `false`, and
+    /// stores nothing, if there is no geometry under `key`.
+    pub fn geo_set_props(&mut self, key: &str, properties: Properties) -> bool {
+        if !self.contains_key(key) {
+            return false;
+        }
+        self.store_props(key, properties);
+        true
+    }
+
+    /// Builds a secondary index on the top-level property `field`. Returns
+    /// `false` if the field is already indexed.
+    pub fn create_index(&mut self, field: &str) -> bool {
+        if self.indexes.contains_key(field) {
+            return false;
+        }
+        let mut index = PropertyIndex::default();
+        for (key, properties) in &self.properties {
+            if let Some(value) = properties.get(field) {
+                index.insert(value, key);
+            }
+        }
+        self.indexes.insert(field.to_string(), index);
+        true
+    }
+
+    /// Drops the secondary index on `field`. Returns `false` if there was none.
+    pub fn drop_index(&mut self, field: &str) -> bool {
+        self.indexes.remove(field).is_some()
+    }
+
+    /// Indexes every key by its H3 cell at `resolution`, replacing an index
+    /// at any other resolution. Returns `false` if there already is one at
+    /// `resolution`.
+    pub fn create_h3_index(&mut self, resolution: Resolution) -> bool {
+        if self.h3_resolution() == Some(resolution) {
+            return false;
+        }
+        let mut index = H3Index::new(resolution);
+        for (key, geometry) in &self.geometries {
+            index.insert(key, geometry);
+        }
+        self.h3 = Some(index);
+        true
+    }
+
+    /// Drops the H3 index. Returns `false` if there was none.
+    pub fn drop_h3_index(&mut self) -> bool {
+        self.h3.take().is_some()
+    }
+
+    /// The resolution of the H3 index, if there is one.
+    pub fn h3_resolution(&self) -> Option<Resolution> {
+        self.h3.as_ref().map(H3Index::resolution)
+    }
+
+    /// The H3 cell at `resolution` of the geometry under `key`. See
+    /// `geometry_cell`.
+    pub fn geo_h3_cell(&self, key: &str, resolution: Resolution) -> Option<CellIndex> {
+        self.geo_geometry(key)
+            .map(|geometry| geometry_cell(geometry, resolution))
+    }
+
+    /// The keys, sorted, whose cell lies inside `cell` or one of the cells up
+    /// to `k` steps away from it, at the resolution of `cell`. Returns `None`
+    /// if there is no H3 index to answer from.
+    pub fn geo_h3_keys(&self, cell: CellIndex, k: u32) -> Option<Vec<String>> {
+        let index = self.h3.as_ref()?;
+        let now = now_ms();
+        let mut keys: Vec<String> = cell
+            .grid_disk::<Vec<_>>(k)
+            .into_iter()
+            .flat_map(|cell| {
+                // Below the index resolution the lookup only narrows things
+                // down to the parent cell.
+                let exact = cell.resolution() <= index.resolution();
+                index.lookup(cell).filter(move |key| {
+                    exact || geometry_cell(&self.geometries[*key], cell.resolution()) == cell
+                })
+            })
+            .filter(|key| self.is_live(key, now))
+            .cloned()
+            .collect();
+        keys.sort();
+        Some(keys)
+    }
+
+    /// The cells at `resolution` that fill the polygon under `key`, or `None`
+    /// if there is no such key. See `polyfill`.
+    pub fn geo_h3_polyfill(
+        &self,
+        key: &str,
+        resolution: Resolution,
+    ) -> Option<Result<Vec<CellIndex>, PolyfillError>> {
+        self.geo_geometry(key)
+            .map(|geometry| polyfill(geometry, resolution))
+    }
+
+    /// The indexed property fields, sorted.
+    pub fn indexed_fields(&self) -> Vec<&String> {
+        let mut fields: Vec<&String> = self.indexes.keys().collect();
+        fields.sort();
+        fields
+    }
+
+    /// Like `geo_search`, but only returns keys whose properties pass every
+    /// filter. `plan_search` decides whether to start from the R-trees or from
+    /// a secondary index; both give the same keys.
+    pub fn geo_search_where(
+        &self,
+        lat: f64,
+        lon: f64,
+        radius: f64,
+        filters: &[Filter],
+    ) -> Vec<String> {
+        self.radius_hits(Point::new(lon, lat), radius, filters)
+            .map(|hit| hit.key)
+            .collect()
+    }
+
+    /// Like `geo_search_where`, but returns each hit with its distance and
+    /// sorts and limits them as `options` asks.
+    pub fn geo_search_hits(
+        &self,
+        lat: f64,
+        lon: f64,
+        radius: f64,
+        filters: &[Filter],
+        options: &SearchOptions,
+    ) -> Vec<SearchHit> {
+        let hits = self.radius_hits(Point::new(lon, lat), radius, filters);
+        let mut hits: Vec<SearchHit> = match options.count {
+            Some(count) if options.any => hits.take(count).collect(),
+            _ => hits.collect(),
+        };
+
+        // Keeping the closest `count` hits means sorting them, like Redis.
+        let order = match (options.order, options.count) {
+            (None, Some(_)) if !options.any => Some(SortOrder::Asc),
+            (order, _) => order,
+        };
+        match order {
+            Some(SortOrder::Asc) => hits.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Some(SortOrder::Desc) => hits.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            None => {}
+        }
+        if let Some(count) = options.count {
+            hits.truncate(count);
+        }
+        hits
+    }
+
+    /// Every live key within `radius` metres of `center` whose properties pass
+    /// `filters`, with its distance. The hits are found lazily, so a caller
+    /// that only needs a few of them stops the search early.
+    fn radius_hits<'a>(
+        &'a self,
+        center: Point<f64>,
+        radius: f64,
+        filters: &'a [Filter],
+    ) -> Box<dyn Iterator<Item = SearchHit> + 'a> {
+        let now = now_ms();
+        let passes = move |key: &str| {
+            self.is_live(key, now)
+                && filters
+                    .iter()
+                    .all(|filter| filter.matches(self.properties.get(key)))
+        };
+        let within = move |key: &String, distance: f64| {
+            (distance <= radius).then(|| SearchHit {
+                key: key.clone(),
+                distance,
+            })
+        };
+
+        if let Some((_, candidates)) = self.choose_index(center, radius, filters) {
+            return Box::new(
+                candidates
+                    .into_iter()
+                    .filter(move |key| passes(key))
+                    .filter_map(move |key| {
+                        within(key, haversine_distance_to(&center, &self.geometries[key]))
+                    }),
+            );
+        }
+
+        let points = radius_envelopes(center, radius)
+            .into_iter()
+            .flat_map(move |envelope| self.point_tree.locate_in_envelope(&envelope))
+            .filter(move |point| passes(&point.data))
+            .filter_map(move |point| within(&point.data, point.geom().haversine_distance(&center)));
+
+        // Shapes whose bounding box touches the radius envelope, kept if their
+        // closest part is really within the radius. A shape can straddle both
+        // envelopes of a search across the antimeridian.
+        let mut seen = HashSet::new();
+        let shapes = radius_envelopes(center, radius)
+            .into_iter()
+            .flat_map(move |envelope| self.shape_tree.locate_in_envelope_intersecting(&envelope))
+            .filter(move |shape| seen.insert(&shape.data) && passes(&shape.data))
+            .filter_map(move |shape| {
+                within(
+                    &shape.data,
+                    haversine_distance_to(&center, &self.geometries[&shape.data]),
+                )
+            });
+
+        Box::new(points.chain(shapes))
+    }
+
+    /// Picks how `geo_search_where` runs: from the index whose filter matches
+    /// the fewest keys, if that is fewer than the spatial search is expected
+    /// to visit.
+    pub fn plan_search(&self, lat: f64, lon: f64, radius: f64, filters: &[Filter]) -> QueryPlan {
+        match self.choose_index(Point::new(lon, lat), radius, filters) {
+            Some((filter, _)) => QueryPlan::Index {
+                field: filter.field.clone(),
+            },
+            None => QueryPlan::Spatial,
+        }
+    }
+
+    fn choose_index<'a>(
+        &'a self,
+        center: Point<f64>,
+        radius: f64,
+        filters: &'a [Filter],
+    ) -> Option<(&'a Filter, Vec<&'a String>)> {
+        let spatial_estimate = self.spatial_estimate(center, radius);
+        filters
+            .iter()
+            .filter_map(|filter| {
+                let index = self.indexes.get(&filter.field)?;
+                Some((filter, index.lookup(filter.op, &filter.value)?))
+            })
+            .min_by_key(|(_, candidates)| candidates.len())
+            .filter(|(_, candidates)| (candidates.len() as f64) < spatial_estimate)
+    }
+
+    /// Roughly how many keys a radius search visits: the share of the data's
+    /// bounding box that the search envelopes cover, times the key count.
+    fn spatial_estimate(&self, center: Point<f64>, radius: f64) -> f64 {
+        let total = self.geometries.len() as f64;
+        let mut bounds = self.point_tree.root().envelope();
+        bounds.merge(&self.shape_tree.root().envelope());
+        let data_area = bounds.area();
+        if !data_area.is_finite() || data_area <= 0.0 {
+            return total;
+        }
+        let query_area: f64 = radius_envelopes(center, radius)
+            .iter()
+            .map(|envelope| envelope.intersection_area(&bounds))
+            .sum();
+        total * (query_area / data_area).min(1.0)
+    }
+
+    /// Sets `key` to expire at `deadline`, in Unix milliseconds. Returns
+    /// `false` if there is no live key to expire.
+    pub fn geo_expire_at(&mut self, key: &str, deadline: u64) -> bool {
+        if !self.contains_key(key) {
+            return false;
+        }
+        self.expirations.insert(key.to_string(), deadline);
+        true
+    }
+
+    /// Replays a logged `geo_add`. Whether the key had expired was settled by
+    /// the clock when the write was logged, and an expired key is logged as
+    /// deleted before it is added again, so unlike `geo_add` this never
+    /// drops the properties of a key whose old deadline has passed since.
+    pub fn restore_geometry(
+        &mut self,
+        key: String,
+        geometry: Geometry<f64>,
+    ) -> Result<AddOutcome, GeometryError> {
+        validate_geometry(&geometry)?;
+        Ok(self.store_geometry(key, geometry))
+    }
+
+    /// Replays a logged `geo_set_props`, on any stored key, live or not.
+    pub fn restore_props(&mut self, key: &str, properties: Properties) -> bool {
+        if !self.geometries.contains_key(key) {
+            return false;
+        }
+        self.store_props(key, properties);
+        true
+    }
+
+    /// Replays a logged `geo_expire_at`, on any stored key, live or not.
+    pub fn restore_expiry(&mut self, key: &str, deadline: u64) -> bool {
+        if !self.geometries.contains_key(key) {
+            return false;
+        }
+        self.expirations.insert(key.to_string(), deadline);
+        true
+    }
+
+    pub fn geo_ttl(&self, key: &str) -> KeyTtl {
+        let now = now_ms();
+        if !self.geometries.contains_key(key) || !self.is_live(key, now) {
+            return KeyTtl::Missing;
+        }
+        match self.expirations.get(key) {
+            Some(&deadline) => KeyTtl::ExpiresIn(Duration::from_millis(deadline - now)),
+            None => KeyTtl::Persistent,
+        }
+    }
+
+    /// Whether `purge_expired` would remove anything at `now`.
+    pub fn has_expired_keys(&self, now: u64) -> bool {
+        self.expirations.values().any(|&deadline| deadline <= now)
+    }
+
+    /// Removes every key whose deadline is at or before `now` and returns
+    /// their names.
+    pub fn purge_expired(&mut self, now: u64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .expirations
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.geo_del(key);
+        }
+        expired
+    }
+
+    /// Removes `key` from the map along with its R-tree entry, properties and
+    /// expiry. Returns `true` if the key existed, even if it had expired.
+    pub fn geo_del(&mut self, key: &str) -> bool {
+        self.expirations.remove(key);
+        match self.geometries.remove(key) {
+            Some(geometry) => {
+                self.unindex(key, &geometry);
+                if let Some(properties) = self.properties.remove(key) {
+                    self.unindex_props(key, &properties);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_live(&self, key: &str, now: u64) -> bool {
+        self.expirations
+            .get(key)
+            .is_none_or(|&deadline| deadline > now)
+    }
+
+    /// Stores `geometry` under `key` and clears its expiry, dropping any
+    /// previous geometry from the R-trees first.
+    fn store_geometry(&mut self, key: String, geometry: Geometry<f64>) -> AddOutcome {
+        self.expirations.remove(&key);
+        let outcome = match self.geometries.remove(&key) {
+            Some(previous) => {
+                self.unindex(&key, &previous);
+                AddOutcome::Updated
+            }
+            None => AddOutcome::Created,
+        };
+        self.index(&key, &geometry);
+        self.geometries.insert(key, geometry);
+        outcome
+    }
+
+    /// Replaces the properties of `key`. The previous ones leave the
+    /// secondary indexes before the new ones go in, so a value both share
+    /// stays indexed.
+    fn store_props(&mut self, key: &str, properties: Properties) {
+        if let Some(previous) = self.properties.remove(key) {
+            self.unindex_props(key, &previous);
+        }
+        for (field, index) in self.indexes.iter_mut() {
+            if let Some(value) = properties.get(field) {
+                index.insert(value, key);
+            }
+        }
+        self.properties.insert(key.to_string(), properties);
+    }
+
+    fn unindex_props(&mut self, key: &str, properties: &Properties) {
+        for (field, index) in self.indexes.iter_mut() {
+            if let Some(value) = properties.get(field) {
+                index.remove(value, key);
+            }
+        }
+    }
+
+    fn index(&mut self, key: &str, geometry: &Geometry<f64>) {
+        if let Some(index) = &mut self.h3 {
+            index.insert(key, geometry);
+        }
+        if let Geometry::Point(point) = geometry {
+            self.point_tree
+                .insert(PointEntry::new(*point, key.to_string()));
+        } else if let Some(entry) = shape_entry(geometry, key) {
+            self.shape_tree.insert(entry);
+        }
+    }
+
+    fn unindex(&mut self, key: &str, geometry: &Geometry<f64>) {
+        if let Some(index) = &mut self.h3 {
+            index.remove(key, geometry);
+        }
+        if let Geometry::Point(point) = geometry {
+            self.point_tree
+                .remove(&PointEntry::new(*point, key.to_string()));
+        } else if let Some(entry) = shape_entry(geometry, key) {
+            self.shape_tree.remove(&entry);
+        }
+    }
+}
+
+/// Builds the R-tree entry for a non-point geometry. Empty geometries have no
+/// bounding box and are not indexed.
+fn shape_entry(geometry: &Geometry<f64>, key: &str) -> Option<ShapeEntry> {
+    let bounds = geometry.bounding_rect()?;
+    let envelope = AABB::from_corners(bounds.min().into(), bounds.max().into());
+    Some(ShapeEntry::new(Rectangle::from(envelope), key.to_string()))
+}
+
+/// Walks `tree` in degree-plane nearest-neighbour order, merging entries into
+/// `hits` (kept sorted by geodesic distance and capped at `k`). The walk stops
+/// as soon as no remaining entry can beat the current `k`-th hit, or
+/// `max_distance` when fewer than `k` hits have been found. Entries `distance`
+/// returns `None` for, such as expired keys, are skipped.
+fn nearest_in_tree<R>(
+    tree: &RTree<GeomWithData<R, String>>,
+    center: Point<f64>,
+    k: usize,
+    max_distance: Option<f64>,
+    hits: &mut Vec<SearchHit>,
+    distance: impl Fn(&GeomWithData<R, String>) -> Option<f64>,
+) where
+    R: RTreeObject<Envelope = AABB<Point<f64>>> + PointDistance,
+{
+    let limit = |hits: &Vec<SearchHit>| match hits.get(k - 1) {
+        Some(kth) => kth.distance,
+        None => max_distance.unwrap_or(f64::INFINITY),
+    };
+    let mut reach_2 = envelope_reach_2(center, limit(hits));
+
+    for (entry, distance_2) in tree.nearest_neighbor_iter_with_distance_2(&center) {
+        if distance_2 > reach_2 {
+            break;
+        }
+        let Some(distance) = distance(entry) else {
+            continue;
+        };
+        if distance > limit(hits) {
+            continue;
+        }
+
+        let index = hits.partition_point(|hit| hit.distance <= distance);
+        hits.insert(
+            index,
+            SearchHit {
+                key: entry.data.clone(),
+                distance,
+            },
+        );
+        hits.truncate(k);
+        reach_2 = envelope_reach_2(center, limit(hits));
     }
 }
@@ -0,0 +1,459 @@
+use geommdb::geospatial::{geometry_from_coords, AxisOrder, DistanceMethod, DistanceUnit};
+use geommdb::network::command::{parse_command, Command, CommandError, HitFields};
+use geommdb::properties::{parse_properties, CompareOp, Filter, PropValue};
+use geommdb::storage::{AddCondition, BoxMatch, GeometryError, SearchOptions, SortOrder};
+
+fn parse(input: &str) -> Result<Command, CommandError> {
+    parse_command(input, AxisOrder::LatLon)
+}
+
+#[test]
+fn test_parse_expire_times() {
+    assert_eq!(
+        parse("GEOADD fleet v1 EX 60 1 2"),
+        Ok(Command::GeoAdd {
+            collection: "fleet".to_string(),
+            key: "v1".to_string(),
+            geometry: geometry_from_coords(&[(1.0, 2.0)]).unwrap(),
+            properties: None,
+            condition: None,
+            expire_seconds: Some(60),
+        })
+    );
+    assert!(matches!(
+        parse("GEOADD fleet v1 1 2"),
+        Ok(Command::GeoAdd {
+            expire_seconds: None,
+            ..
+        })
+    ));
+    // Too far out is only known against the clock; the handler rejects it.
+    assert!(matches!(
+        parse("GEOADD fleet v1 EX 18446744073709551615 1 2"),
+        Ok(Command::GeoAdd {
+            expire_seconds: Some(u64::MAX),
+            ..
+        })
+    ));
+    assert_eq!(
+        parse("GEOADD fleet v1 EX 0 1 2"),
+        Err(CommandError::InvalidExpireTime)
+    );
+    assert_eq!(
+        parse("GEOADD fleet v1 EX -1 1 2"),
+        Err(CommandError::InvalidNumber("-1".to_string()))
+    );
+    assert_eq!(
+        parse("GEOADD fleet v1 EX 18446744073709551616 1 2"),
+        Err(CommandError::InvalidNumber(
+            "18446744073709551616".to_string()
+        ))
+    );
+
+    assert_eq!(
+        parse("GEOEXPIRE fleet v1 60"),
+        Ok(Command::GeoExpire {
+            collection: "fleet".to_string(),
+            key: "v1".to_string(),
+            seconds: 60,
+        })
+    );
+    // Like Redis `EXPIRE key 0`, this expires the key right away.
+    assert!(matches!(
+        parse("GEOEXPIRE fleet v1 0"),
+        Ok(Command::GeoExpire { seconds: 0, .. })
+    ));
+    assert_eq!(
+        parse("GEOEXPIRE fleet v1 soon"),
+        Err(CommandError::InvalidNumber("soon".to_string()))
+    );
+    assert_eq!(parse("GEOEXPIRE fleet v1"), Err(CommandError::Syntax));
+}
+
+#[test]
+fn test_parse_add_conditions() {
+    assert!(matches!(
+        parse("GEOADD fleet v1 1 2"),
+        Ok(Command::GeoAdd {
+            condition: None,
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("GEOADD fleet v1 NX 1 2"),
+        Ok(Command::GeoAdd {
+            condition: Some(AddCondition::IfNotExists),
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("GEOADD fleet v1 XX 1 2"),
+        Ok(Command::GeoAdd {
+            condition: Some(AddCondition::IfExists),
+            ..
+        })
+    ));
+    // Only one condition, and only before the coordinates.
+    assert_eq!(
+        parse("GEOADD fleet v1 NX XX 1 2"),
+        Err(GeometryError::InvalidWkt.into())
+    );
+    assert_eq!(
+        parse("GEOADD fleet v1 1 2 NX"),
+        Err(CommandError::UnpairedCoordinate)
+    );
+    assert_eq!(
+        parse("GEOADD fleet v1 NX"),
+        Err(GeometryError::Empty.into())
+    );
+    assert_eq!(parse("geoadd fleet v1 1 2"), Err(CommandError::Syntax));
+}
+
+#[test]
+fn test_parse_geo_nearest() {
+    assert_eq!(
+        parse("GEONEAREST stores 1 2 5"),
+        Ok(Command::GeoNearest {
+            collection: "stores".to_string(),
+            lat: 1.0,
+            lon: 2.0,
+            k: 5,
+            max_distance: None,
+            unit: DistanceUnit::Metres,
+        })
+    );
+    assert!(matches!(
+        parse("GEONEAREST stores 1 2 5 MAXDIST 300"),
+        Ok(Command::GeoNearest {
+            max_distance: Some(300.0),
+            ..
+        })
+    ));
+    assert_eq!(
+        parse("GEONEAREST stores 1 2 5 MAXDIST"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        parse("GEONEAREST stores 1 2 5 MAXDIST far"),
+        Err(CommandError::InvalidNumber("far".to_string()))
+    );
+    assert_eq!(
+        parse("GEONEAREST stores 1 2 -1"),
+        Err(CommandError::InvalidNumber("-1".to_string()))
+    );
+    assert_eq!(parse("GEONEAREST stores 1 2"), Err(CommandError::Syntax));
+    assert_eq!(
+        parse("GEONEAREST stores NaN 0 5"),
+        Err(GeometryError::NotFinite.into())
+    );
+    assert_eq!(
+        parse("GEONEAREST stores 0 -180.5 5"),
+        Err(GeometryError::LongitudeOutOfRange(-180.5).into())
+    );
+}
+
+#[test]
+fn test_parse_axis() {
+    assert_eq!(
+        parse("AXIS LATLON"),
+        Ok(Command::Axis {
+            order: AxisOrder::LatLon,
+        })
+    );
+    assert_eq!(
+        parse("AXIS LONLAT"),
+        Ok(Command::Axis {
+            order: AxisOrder::LonLat,
+        })
+    );
+    assert_eq!(parse("AXIS lonlat"), Err(CommandError::Syntax));
+    assert_eq!(parse("AXIS XY"), Err(CommandError::Syntax));
+    assert_eq!(parse("AXIS"), Err(CommandError::Syntax));
+
+    // Pairs are read in the connection's order and always come out as
+    // (lat, lon).
+    let containing = Ok(Command::GeoContaining {
+        collection: "stores".to_string(),
+        lat: 1.0,
+        lon: 2.0,
+    });
+    assert_eq!(
+        parse_command("GEOCONTAINING stores 1 2", AxisOrder::LatLon),
+        containing
+    );
+    assert_eq!(
+        parse_command("GEOCONTAINING stores 2 1", AxisOrder::LonLat),
+        containing
+    );
+}
+
+#[test]
+fn test_parse_props() {
+    assert_eq!(
+        parse(r#"GEOADD fleet v1 NX EX 60 1 2 PROPS {"kind": "van", "seats": 3}"#),
+        Ok(Command::GeoAdd {
+            collection: "fleet".to_string(),
+            key: "v1".to_string(),
+            geometry: geometry_from_coords(&[(1.0, 2.0)]).unwrap(),
+            properties: parse_properties(r#"{"kind": "van", "seats": 3}"#),
+            condition: Some(AddCondition::IfNotExists),
+            expire_seconds: Some(60),
+        })
+    );
+    assert_eq!(
+        parse(r#"GEOSETPROPS fleet v1 {"kind": "van"}"#),
+        Ok(Command::GeoSetProps {
+            collection: "fleet".to_string(),
+            key: "v1".to_string(),
+            properties: parse_properties(r#"{"kind": "van"}"#).unwrap(),
+        })
+    );
+    assert_eq!(
+        parse("GEOADD fleet v1 1 2 PROPS"),
+        Err(CommandError::UnpairedCoordinate)
+    );
+    assert_eq!(
+        parse(r#"GEOADD fleet v1 1 2 {"kind": "van"}"#),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        parse("GEOADD fleet v1 1 2 PROPS {kind: van}"),
+        Err(CommandError::InvalidProperties)
+    );
+    assert_eq!(
+        parse("GEOADD fleet v1 1 2 PROPS {} trailing"),
+        Err(CommandError::InvalidProperties)
+    );
+    assert_eq!(parse("GEOSETPROPS fleet v1"), Err(CommandError::Syntax));
+}
+
+#[test]
+fn test_parse_where() {
+    let filters = |input| match parse(input) {
+        Ok(Command::GeoSearch { filters, .. }) => Ok(filters),
+        Ok(other) => panic!("not a search: {:?}", other),
+        Err(e) => Err(e),
+    };
+    assert_eq!(
+        filters("GEOSEARCH stores 1 2 500 WHERE rating >= 4.5 AND open = true AND name != pizza"),
+        Ok(vec![
+            Filter {
+                field: "rating".to_string(),
+                op: CompareOp::Ge,
+                value: PropValue::Number(4.5),
+            },
+            Filter {
+                field: "open".to_string(),
+                op: CompareOp::Eq,
+                value: PropValue::Bool(true),
+            },
+            Filter {
+                field: "name".to_string(),
+                op: CompareOp::Ne,
+                value: PropValue::String("pizza".to_string()),
+            },
+        ])
+    );
+    assert_eq!(
+        filters("GEOSEARCH stores 1 2 500 WHERE rating >> 4"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        filters("GEOSEARCH stores 1 2 500 WHERE rating >="),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        filters("GEOSEARCH stores 1 2 500 WHERE rating >= 4 AND"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        filters("GEOSEARCH stores 1 2 500 WHERE"),
+        Err(CommandError::Syntax)
+    );
+}
+
+#[test]
+fn test_parse_search_options() {
+    assert_eq!(
+        parse("GEOSEARCH stores 1 2 500"),
+        Ok(Command::GeoSearch {
+            collection: "stores".to_string(),
+            lat: 1.0,
+            lon: 2.0,
+            radius: 500.0,
+            unit: DistanceUnit::Metres,
+            fields: HitFields::default(),
+            options: SearchOptions::default(),
+            filters: vec![],
+        })
+    );
+    assert!(matches!(
+        parse("GEOSEARCH stores 1 2 500 WITHTYPE DESC COUNT 3 ANY WITHPROPS WITHDIST WITHCOORD"),
+        Ok(Command::GeoSearch {
+            fields: HitFields {
+                distance: true,
+                coord: true,
+                geometry_type: true,
+                properties: true,
+            },
+            options: SearchOptions {
+                order: Some(SortOrder::Desc),
+                count: Some(3),
+                any: true,
+            },
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("GEOSEARCH stores 1 2 500 ASC COUNT 3"),
+        Ok(Command::GeoSearch {
+            options: SearchOptions {
+                order: Some(SortOrder::Asc),
+                count: Some(3),
+                any: false,
+            },
+            ..
+        })
+    ));
+    assert_eq!(
+        parse("GEOSEARCH stores 1 2 500 COUNT 0"),
+        Err(CommandError::InvalidNumber("0".to_string()))
+    );
+    assert_eq!(
+        parse("GEOSEARCH stores 1 2 500 COUNT few"),
+        Err(CommandError::InvalidNumber("few".to_string()))
+    );
+    assert_eq!(
+        parse("GEOSEARCH stores 1 2 500 COUNT"),
+        Err(CommandError::Syntax)
+    );
+    // `ANY` only means something after `COUNT n`.
+    assert_eq!(
+        parse("GEOSEARCH stores 1 2 500 ANY"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        parse("GEOSEARCH stores 1 2 500 WITHALL"),
+        Err(CommandError::Syntax)
+    );
+}
+
+#[test]
+fn test_parse_units() {
+    assert!(matches!(
+        parse("GEOSEARCH stores 1 2 2 km"),
+        Ok(Command::GeoSearch {
+            radius: 2000.0,
+            unit: DistanceUnit::Kilometres,
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("GEOSEARCH stores 1 2 2 KM WITHDIST"),
+        Ok(Command::GeoSearch {
+            radius: 2000.0,
+            unit: DistanceUnit::Kilometres,
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("GEONEAREST stores 1 2 5 mi"),
+        Ok(Command::GeoNearest {
+            max_distance: None,
+            unit: DistanceUnit::Miles,
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("GEONEAREST stores 1 2 5 MAXDIST 3 km"),
+        Ok(Command::GeoNearest {
+            max_distance: Some(3000.0),
+            unit: DistanceUnit::Kilometres,
+            ..
+        })
+    ));
+    // A unit only goes right after the distance it applies to.
+    assert_eq!(
+        parse("GEOSEARCH stores 1 2 500 ASC km"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        parse("GEONEAREST stores 1 2 5 km MAXDIST 3"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        parse("GEONEAREST stores 1 2 5 parsecs"),
+        Err(CommandError::Syntax)
+    );
+}
+
+#[test]
+fn test_parse_geo_dist() {
+    assert_eq!(
+        parse("GEODIST stores a b"),
+        Ok(Command::GeoDist {
+            collection: "stores".to_string(),
+            key1: "a".to_string(),
+            key2: "b".to_string(),
+            unit: DistanceUnit::Metres,
+            method: DistanceMethod::default(),
+        })
+    );
+    assert!(matches!(
+        parse("GEODIST stores a b ft METHOD vincenty"),
+        Ok(Command::GeoDist {
+            unit: DistanceUnit::Feet,
+            method: DistanceMethod::Vincenty,
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("GEODIST stores a b METHOD GEODESIC"),
+        Ok(Command::GeoDist {
+            unit: DistanceUnit::Metres,
+            method: DistanceMethod::Geodesic,
+            ..
+        })
+    ));
+    assert_eq!(
+        parse("GEODIST stores a b METHOD manhattan"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        parse("GEODIST stores a b METHOD"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(
+        parse("GEODIST stores a b METHOD haversine km"),
+        Err(CommandError::Syntax)
+    );
+    assert_eq!(parse("GEODIST stores a"), Err(CommandError::Syntax));
+}
+
+#[test]
+fn test_parse_geo_search_box() {
+    assert_eq!(
+        parse("GEOSEARCHBOX stores 0 170 10 -170 WITHIN"),
+        Ok(Command::GeoSearchBox {
+            collection: "stores".to_string(),
+            min_lat: 0.0,
+            min_lon: 170.0,
+            max_lat: 10.0,
+            max_lon: -170.0,
+            mode: BoxMatch::Within,
+        })
+    );
+    assert_eq!(
+        parse("GEOSEARCHBOX stores 10 0 0 1"),
+        Err(CommandError::Geometry(GeometryError::InvertedBox {
+            min_lat: 10.0,
+            max_lat: 0.0
+        }))
+    );
+    assert_eq!(
+        parse("GEOSEARCHBOX stores 0 0 95 1"),
+        Err(CommandError::Geometry(GeometryError::LatitudeOutOfRange(
+            95.0
+        )))
+    );
+}
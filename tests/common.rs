@@ -1,16 +1,40 @@
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use tokio::task;
 
 use geommdb::network::{replica::Role, server::start_server};
 
+/// Where the node at `addr` keeps its WAL and snapshots.
+pub fn node_dir(addr: SocketAddr) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "geommdb-node-{}-{}",
+        addr.port(),
+        std::process::id()
+    ))
+}
+
+/// A fresh data directory for the node at `addr`, so nodes in one test run
+/// never share their WAL or snapshots.
+fn data_dir(addr: SocketAddr) -> PathBuf {
+    let dir = node_dir(addr);
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
 pub async fn start_leader(addr: SocketAddr) {
     task::spawn(async move {
-        start_server(addr, None, Role::Leader).await;
+        start_server(addr, None, Role::Leader, data_dir(addr))
+            .await
+            .unwrap();
     });
 }
 
+#[allow(dead_code)]
 pub async fn start_replica(addr: SocketAddr, leader_addr: SocketAddr) {
     task::spawn(async move {
-        start_server(addr, Some(leader_addr), Role::Replica).await;
+        start_server(addr, Some(leader_addr), Role::Replica, data_dir(addr))
+            .await
+            .unwrap();
     });
 }
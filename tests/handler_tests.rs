@@ -0,0 +1,221 @@
+use geommdb::network::handler::MAX_COMMAND_LEN;
+use geommdb::persistence::Persistence;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+mod common;
+
+/// Sends one command line and returns the reply.
+async fn send(stream: &mut TcpStream, command: &str) -> String {
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await
+        .unwrap();
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await.unwrap();
+    String::from_utf8_lossy(&buffer[..n]).to_string()
+}
+
+/// Posts a JSON body to the REST API and returns the status line and the
+/// response body.
+async fn post(path: &str, body: &str) -> (String, String) {
+    let mut stream = TcpStream::connect("127.0.0.1:3030").await.unwrap();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default();
+    (status.to_string(), body.to_string())
+}
+
+// One test per file: every leader serves REST on the same port, so the
+// checks below share a single leader.
+#[tokio::test]
+async fn test_handlers() {
+    // Expired keys stay until a write replaces them.
+    std::env::set_var("EXPIRY_SWEEP_EVERY_X_SECONDS", "3600");
+    let leader_addr = "127.0.0.1:6390".parse().unwrap();
+    common::start_leader(leader_addr).await;
+    sleep(Duration::from_secs(1)).await; // Allow leader to start
+
+    let mut stream = TcpStream::connect(leader_addr).await.unwrap();
+    check_expire_time_limits(&mut stream).await;
+    check_rest_axis().await;
+    check_invalid_query_coordinates(&mut stream).await;
+    check_expired_key_replay(&mut stream, &common::node_dir(leader_addr)).await;
+    check_long_commands(&mut stream, leader_addr).await;
+    check_save(&mut stream, &common::node_dir(leader_addr)).await;
+}
+
+async fn check_expire_time_limits(stream: &mut TcpStream) {
+    assert_eq!(send(stream, "GEOADD fleet v1 1 2").await, "CREATED\n");
+    assert_eq!(
+        send(stream, "GEOADD fleet v2 EX 0 1 2").await,
+        "ERROR invalid expire time\n"
+    );
+    assert_eq!(
+        send(stream, "GEOADD fleet v2 EX 18446744073709551615 1 2").await,
+        "ERROR invalid expire time\n"
+    );
+    assert_eq!(
+        send(stream, "GEOEXPIRE fleet v1 18446744073709551615").await,
+        "ERROR invalid expire time\n"
+    );
+    assert_eq!(
+        post(
+            "/collections/fleet/geoadd",
+            r#"{"key": "v2", "coords": [[1, 2]], "expire_seconds": 0}"#
+        )
+        .await
+        .0,
+        "HTTP/1.1 400 Bad Request"
+    );
+    assert_eq!(
+        post(
+            "/collections/fleet/geo/v1/expire",
+            r#"{"seconds": 18446744073709551615}"#
+        )
+        .await
+        .0,
+        "HTTP/1.1 400 Bad Request"
+    );
+
+    // Nothing was stored, and the server is still answering.
+    assert_eq!(send(stream, "GEOTTL fleet v1").await, "-1\n");
+    assert_eq!(send(stream, "GEOTTL fleet v2").await, "-2\n");
+    assert_eq!(send(stream, "GEOEXPIRE fleet v1 60").await, "OK\n");
+}
+
+/// REST geosearch writes coordinates in the requested axis order.
+async fn check_rest_axis() {
+    let search = r#"{"lat": 1, "lon": 2, "radius": 1000, "with_coord": true}"#;
+    assert_eq!(
+        post("/collections/fleet/geosearch", search).await.1,
+        r#"[{"coordinates":[[1.0,2.0]],"key":"v1"}]"#
+    );
+    assert_eq!(
+        post("/collections/fleet/geosearch?axis=LONLAT", search)
+            .await
+            .1,
+        r#"[{"coordinates":[[2.0,1.0]],"key":"v1"}]"#
+    );
+}
+
+/// Query coordinates are checked before the database is locked, so a bad one
+/// cannot take the server down.
+async fn check_invalid_query_coordinates(stream: &mut TcpStream) {
+    assert_eq!(
+        send(stream, "GEONEAREST fleet NaN 0 5").await,
+        "ERROR coordinates must be finite numbers\n"
+    );
+    assert_eq!(
+        send(stream, "GEOSEARCH fleet 91 0 1000").await,
+        "ERROR latitude 91 is outside [-90, 90]\n"
+    );
+    assert_eq!(
+        send(stream, "GEOCONTAINING fleet 0 inf").await,
+        "ERROR coordinates must be finite numbers\n"
+    );
+    assert_eq!(
+        send(stream, "GEOSEARCHBOX fleet 0 0 1 181").await,
+        "ERROR longitude 181 is outside [-180, 180]\n"
+    );
+    for (path, body) in [
+        ("geonearest", r#"{"lat": 91, "lon": 0, "k": 5}"#),
+        ("geosearch", r#"{"lat": 0, "lon": -181, "radius": 1000}"#),
+        ("geocontaining", r#"{"lat": -91, "lon": 0}"#),
+        (
+            "geosearchbox",
+            r#"{"min_lat": 0, "min_lon": 0, "max_lat": 95, "max_lon": 1}"#,
+        ),
+    ] {
+        let path = format!("/collections/fleet/{}", path);
+        assert_eq!(
+            post(&path, body).await.0,
+            "HTTP/1.1 400 Bad Request",
+            "{}",
+            path
+        );
+    }
+    assert!(send(stream, "GEONEAREST fleet 1 2 5")
+        .await
+        .starts_with("v1 "));
+}
+
+/// Re-adding a key that expired before the sweep removed it starts it afresh,
+/// without its old properties, and a replay of the log agrees.
+async fn check_expired_key_replay(stream: &mut TcpStream, dir: &Path) {
+    assert_eq!(
+        send(
+            stream,
+            r#"GEOADD fleet ghost EX 1 10 10 PROPS {"driver": "ana"}"#
+        )
+        .await,
+        "CREATED\n"
+    );
+    sleep(Duration::from_millis(1100)).await;
+    assert_eq!(send(stream, "GEOADD fleet ghost 10 10").await, "CREATED\n");
+
+    let recovery = Persistence::recover(dir).unwrap();
+    let db = recovery.collections.get("fleet").unwrap();
+    assert!(db.contains_key("ghost"));
+    assert_eq!(db.geo_props("ghost"), None);
+}
+
+/// Commands are read a line at a time, however many reads a line takes.
+async fn check_long_commands(stream: &mut TcpStream, leader_addr: SocketAddr) {
+    let ring: Vec<String> = (0..=120)
+        .map(|i| {
+            let angle = (i % 120) as f64 * std::f64::consts::TAU / 120.0;
+            format!("{:.6} {:.6}", 30.0 + angle.sin(), 30.0 + angle.cos())
+        })
+        .collect();
+    let command = format!("GEOADD fleet zone POLYGON(({}))", ring.join(", "));
+    assert!(command.len() > 2048);
+    assert_eq!(send(stream, &command).await, "CREATED\n");
+    assert_eq!(send(stream, "GEOCONTAINING fleet 30 30").await, "zone\n");
+
+    // A line too long to be a command closes the connection.
+    let mut other = TcpStream::connect(leader_addr).await.unwrap();
+    let flood = vec![b'x'; MAX_COMMAND_LEN + 1];
+    other.write_all(&flood).await.unwrap();
+    let mut reply = String::new();
+    other.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(
+        reply,
+        format!("ERROR command longer than {} bytes\n", MAX_COMMAND_LEN)
+    );
+    assert_eq!(send(stream, "GEOTTL fleet zone").await, "-1\n");
+}
+
+/// `SAVE` writes a snapshot before replying, over TCP and REST alike.
+async fn check_save(stream: &mut TcpStream, dir: &Path) {
+    let snapshots = || {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("snapshot.") && name.ends_with(".bincode")
+            })
+            .count()
+    };
+    assert_eq!(snapshots(), 0);
+    assert_eq!(send(stream, "SAVE").await, "OK\n");
+    assert_eq!(snapshots(), 1);
+
+    assert_eq!(send(stream, "GEOADD fleet v3 3 4").await, "CREATED\n");
+    assert_eq!(post("/save", "").await.0, "HTTP/1.1 200 OK");
+    assert_eq!(snapshots(), 2);
+    assert_eq!(send(stream, "GEOTTL fleet v3").await, "-1\n");
+}
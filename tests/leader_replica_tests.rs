@@ -22,17 +22,17 @@ async fn test_leader_replica_interaction() {
 
     let mut leader_stream = TcpStream::connect(leader_addr).await.unwrap();
     leader_stream
-        .write_all(b"GEOADD point1 40.7128 -74.0060\n")
+        .write_all(b"GEOADD points point1 40.7128 -74.0060\n")
         .await
         .unwrap();
     let mut buffer = [0; 1024];
     let n = leader_stream.read(&mut buffer).await.unwrap();
     let response = String::from_utf8_lossy(&buffer[..n]);
-    assert_eq!(response, "OK\n");
+    assert_eq!(response, "CREATED\n");
 
     // let mut replica_stream = TcpStream::connect(replica_addr).await.unwrap();
     // replica_stream
-    //     .write_all(b"GEOSEARCH 40.7128 -74.0060 10\n")
+    //     .write_all(b"GEOSEARCH points 40.7128 -74.0060 10\n")
     //     .await
     //     .unwrap();
     // let n = replica_stream.read(&mut buffer).await.unwrap();
@@ -0,0 +1,532 @@
+use geommdb::collections::GeoCollections;
+use geommdb::geospatial::geometry_from_coords;
+use geommdb::persistence::{self, DataDir, PendingSnapshot, Persistence, SnapshotRule, WalEntry};
+use geommdb::properties::parse_properties;
+use geommdb::storage::{now_ms, KeyTtl};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("geommdb-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+fn add(key: &str, lat: f64, lon: f64) -> WalEntry {
+    WalEntry::GeoAdd {
+        collection: "places".to_string(),
+        key: key.to_string(),
+        geometry: geometry_from_coords(&[(lat, lon)]).unwrap(),
+        properties: None,
+        expires_at: None,
+    }
+}
+
+fn write_wal(dir: &Path, entries: Vec<WalEntry>) {
+    let mut persistence = Persistence::open(DataDir::lock(dir).unwrap()).unwrap();
+    for entry in entries {
+        persistence.log_entry(entry).unwrap();
+    }
+}
+
+fn open(dir: &Path, snapshots_kept: usize) -> Persistence {
+    let mut persistence = Persistence::open(DataDir::lock(dir).unwrap()).unwrap();
+    persistence.set_snapshots_kept(snapshots_kept);
+    persistence
+}
+
+/// Writes `snapshot` and records how it went, as `persistence::save` does.
+fn finish(persistence: &mut Persistence, snapshot: &PendingSnapshot) {
+    let result = snapshot.write();
+    persistence.finish_snapshot(snapshot, &result);
+    result.unwrap();
+}
+
+fn replay(dir: &Path) -> std::io::Result<GeoCollections> {
+    Persistence::recover(dir).map(|recovery| recovery.collections)
+}
+
+/// The files in `dir` whose names start with `prefix`.
+fn files(dir: &Path, prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    names.sort();
+    names
+}
+
+/// The WAL files in `dir` other than the active one.
+fn rotated(dir: &Path) -> Vec<String> {
+    files(dir, "wal.log.")
+}
+
+fn keys(collections: &GeoCollections) -> Vec<String> {
+    let db = collections.get("places").unwrap();
+    let mut keys = db.geo_search(0.0, 0.0, 20_000_000.0);
+    keys.sort();
+    keys
+}
+
+#[test]
+fn test_wal_round_trip() {
+    let dir = data_dir("round-trip");
+    let path = dir.join("wal.log");
+    // 10.0 is 0x4024000000000000 and the key length is 10: both put 0x0A
+    // bytes in the records, which a line-based log splits on.
+    write_wal(
+        &dir,
+        vec![
+            add("newline-10", 10.0, 10.0),
+            add("b", -33.8688, 151.2093),
+            WalEntry::GeoDel {
+                collection: "places".to_string(),
+                keys: vec!["b".to_string()],
+            },
+            add("c", 51.5074, -0.1278),
+        ],
+    );
+    assert!(fs::read(&path).unwrap().contains(&b'\n'));
+
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["c", "newline-10"]);
+
+    // Reopening appends after the existing records instead of starting over.
+    write_wal(&dir, vec![add("d", 1.0, 1.0)]);
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["c", "d", "newline-10"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_torn_tail() {
+    let dir = data_dir("torn-tail");
+    let path = dir.join("wal.log");
+    write_wal(&dir, vec![add("a", 1.0, 1.0), add("b", 2.0, 2.0)]);
+    let intact = fs::read(&path).unwrap();
+
+    // A record cut off part way through is dropped, and the file is cut back
+    // to the last whole record so new entries follow it.
+    write_wal(&dir, vec![add("torn", 3.0, 3.0)]);
+    let full = fs::read(&path).unwrap();
+    fs::write(&path, &full[..full.len() - 5]).unwrap();
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["a", "b"]);
+    assert_eq!(fs::read(&path).unwrap(), intact);
+
+    write_wal(&dir, vec![add("c", 4.0, 4.0)]);
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["a", "b", "c"]);
+
+    // So is a last record whose checksum does not match.
+    let mut bytes = fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    fs::write(&path, &bytes).unwrap();
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["a", "b"]);
+    assert_eq!(fs::read(&path).unwrap(), intact);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_torn_header() {
+    let dir = data_dir("torn-header");
+    let path = dir.join("wal.log");
+
+    // A crash right after the active segment was created leaves it empty or
+    // with part of its header; it holds nothing yet and is started again.
+    fs::write(&path, b"").unwrap();
+    assert!(replay(&dir).unwrap().collection_names().is_empty());
+    write_wal(&dir, vec![add("a", 1.0, 1.0), add("b", 2.0, 2.0)]);
+
+    // The same crash just after a snapshot rotated the log.
+    fs::rename(&path, dir.join("wal.log.1")).unwrap();
+    let header = b"GEOMMWAL\x03\x00\x00\x00\x03";
+    fs::write(&path, header).unwrap();
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["a", "b"]);
+    assert!(fs::read(&path).unwrap().is_empty());
+
+    write_wal(&dir, vec![add("c", 3.0, 3.0)]);
+    assert_eq!(&fs::read(&path).unwrap()[12..20], &3u64.to_le_bytes());
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["a", "b", "c"]);
+
+    // Anything that is not the start of a header is still an error.
+    fs::write(&path, b"GEOMMXXX").unwrap();
+    assert_eq!(replay(&dir).unwrap_err().kind(), ErrorKind::InvalidData);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_corruption() {
+    let dir = data_dir("corruption");
+    let path = dir.join("wal.log");
+    write_wal(&dir, vec![add("a", 1.0, 1.0), add("b", 2.0, 2.0)]);
+
+    // A bad record with more after it is not a torn write, and replay does
+    // not skip over it or touch the file.
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[30] ^= 0xFF;
+    fs::write(&path, &bytes).unwrap();
+    let error = replay(&dir).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    // Nor is a middle record whose length points past the end of the file:
+    // the length has its own checksum, so it is not mistaken for a torn tail.
+    fs::remove_file(&path).unwrap();
+    let entries = ["a", "b", "c", "d", "e"].map(|key| add(key, 1.0, 1.0));
+    write_wal(&dir, entries.into());
+    let mut bytes = fs::read(&path).unwrap();
+    let record_len = (bytes.len() - 20) / 5;
+    let third = 20 + 2 * record_len;
+    bytes[third..third + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&path, &bytes).unwrap();
+    let error = replay(&dir).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains("corrupt WAL record"));
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    // Only the active segment can end in a torn record; a rotated one was
+    // synced whole, so one cut short there is damage, and is left as it is.
+    fs::remove_file(&path).unwrap();
+    write_wal(&dir, vec![add("a", 1.0, 1.0), add("b", 2.0, 2.0)]);
+    let rotated = dir.join("wal.log.1");
+    fs::rename(&path, &rotated).unwrap();
+    let bytes = fs::read(&rotated).unwrap();
+    let torn = &bytes[..bytes.len() - 5];
+    fs::write(&rotated, torn).unwrap();
+    let error = replay(&dir).unwrap_err();
+    assert!(error.to_string().contains("torn WAL record"));
+    assert_eq!(fs::read(&rotated).unwrap(), torn);
+    fs::remove_file(&rotated).unwrap();
+
+    fs::write(&path, b"not a log at all").unwrap();
+    assert_eq!(replay(&dir).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let mut bytes = b"GEOMMWAL".to_vec();
+    bytes.extend(1u32.to_le_bytes());
+    fs::write(&path, &bytes).unwrap();
+    let error = replay(&dir).unwrap_err();
+    assert!(error.to_string().contains("version 1"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_wal_replay_ignores_clock() {
+    let dir = data_dir("replay-clock");
+    let props = || parse_properties(r#"{"driver": "ana"}"#).unwrap();
+    // Both keys were written with a deadline that has passed by the time the
+    // log is replayed, but later records kept them alive.
+    let past = now_ms() - 1_000;
+    let later = now_ms() + 60_000;
+    let expiring = |key: &str, properties| WalEntry::GeoAdd {
+        collection: "places".to_string(),
+        key: key.to_string(),
+        geometry: geometry_from_coords(&[(1.0, 1.0)]).unwrap(),
+        properties,
+        expires_at: Some(past),
+    };
+    write_wal(
+        &dir,
+        vec![
+            expiring("courier", None),
+            WalEntry::GeoExpire {
+                collection: "places".to_string(),
+                key: "courier".to_string(),
+                at: later,
+            },
+            WalEntry::GeoSetProps {
+                collection: "places".to_string(),
+                key: "courier".to_string(),
+                properties: props(),
+            },
+            expiring("depot", Some(props())),
+            // Replacing the geometry clears the expiry and keeps properties.
+            add("depot", 2.0, 2.0),
+        ],
+    );
+
+    let collections = replay(&dir).unwrap();
+    let db = collections.get("places").unwrap();
+    match db.geo_ttl("courier") {
+        KeyTtl::ExpiresIn(remaining) => assert!(remaining > Duration::from_secs(50)),
+        ttl => panic!("unexpected ttl {:?}", ttl),
+    }
+    assert_eq!(db.geo_props("courier"), Some(&props()));
+    assert_eq!(db.geo_ttl("depot"), KeyTtl::Persistent);
+    assert_eq!(db.geo_props("depot"), Some(&props()));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_snapshot_compacts_wal() {
+    let dir = data_dir("compaction");
+    let path = dir.join("wal.log");
+    let mut persistence = open(&dir, 1);
+    persistence.log_entry(add("a", 1.0, 1.0)).unwrap();
+    persistence.log_entry(add("b", 2.0, 2.0)).unwrap();
+    assert_eq!(persistence.changes_since_snapshot(), 2);
+
+    let snapshot = persistence.begin_snapshot(&replay(&dir).unwrap()).unwrap();
+    assert_eq!(snapshot.seq(), 2);
+    assert_eq!(rotated(&dir), vec!["wal.log.1"]);
+    let error = persistence
+        .begin_snapshot(&GeoCollections::new())
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::ResourceBusy);
+    finish(&mut persistence, &snapshot);
+    assert_eq!(persistence.changes_since_snapshot(), 0);
+
+    // The snapshot took over the WAL's entries, leaving just a header.
+    assert!(rotated(&dir).is_empty());
+    assert_eq!(fs::read(&path).unwrap().len(), 20);
+
+    persistence.log_entry(add("c", 3.0, 3.0)).unwrap();
+    drop(persistence);
+    write_wal(&dir, vec![add("d", 4.0, 4.0)]);
+    let collections = replay(&dir).unwrap();
+    assert_eq!(keys(&collections), vec!["a", "b", "c", "d"]);
+
+    // A snapshot taken with nothing logged since the last one has no segment
+    // to move aside.
+    let mut persistence = open(&dir, 1);
+    let snapshot = persistence.begin_snapshot(&collections).unwrap();
+    assert_eq!(snapshot.seq(), 4);
+    finish(&mut persistence, &snapshot);
+    let snapshot = persistence.begin_snapshot(&collections).unwrap();
+    assert!(rotated(&dir).is_empty());
+    finish(&mut persistence, &snapshot);
+    assert_eq!(keys(&replay(&dir).unwrap()), vec!["a", "b", "c", "d"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_snapshot_interrupted() {
+    let dir = data_dir("interrupted");
+    write_wal(&dir, vec![add("a", 1.0, 1.0)]);
+    let mut persistence = open(&dir, 1);
+    let snapshot = persistence.begin_snapshot(&replay(&dir).unwrap()).unwrap();
+    finish(&mut persistence, &snapshot);
+
+    // A crash before the snapshot is written leaves its segment behind, and
+    // recovery replays it ahead of the active one.
+    persistence.log_entry(add("b", 2.0, 2.0)).unwrap();
+    let unwritten = persistence.begin_snapshot(&replay(&dir).unwrap()).unwrap();
+    drop(unwritten);
+    persistence.log_entry(add("c", 3.0, 3.0)).unwrap();
+    drop(persistence);
+    assert_eq!(rotated(&dir), vec!["wal.log.2"]);
+    assert_eq!(keys(&replay(&dir).unwrap()), vec!["a", "b", "c"]);
+
+    // Reopening carries on numbering after both segments, and the next
+    // snapshot covers and removes them.
+    let mut persistence = open(&dir, 1);
+    persistence.log_entry(add("d", 4.0, 4.0)).unwrap();
+    let snapshot = persistence.begin_snapshot(&replay(&dir).unwrap()).unwrap();
+    assert_eq!(snapshot.seq(), 4);
+    finish(&mut persistence, &snapshot);
+    drop(persistence);
+    assert!(rotated(&dir).is_empty());
+    assert_eq!(keys(&replay(&dir).unwrap()), vec!["a", "b", "c", "d"]);
+
+    // Without the segment between the snapshot and the active WAL, entries
+    // are missing, and recovery refuses to guess.
+    write_wal(&dir, vec![add("e", 5.0, 5.0)]);
+    let mut persistence = open(&dir, 1);
+    let unwritten = persistence.begin_snapshot(&replay(&dir).unwrap()).unwrap();
+    drop(unwritten);
+    drop(persistence);
+    fs::remove_file(dir.join("wal.log.5")).unwrap();
+    let error = replay(&dir).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains("missing entries 5 to 5"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_snapshot_retention() {
+    let dir = data_dir("retention");
+    let mut persistence = open(&dir, 2);
+    for (key, at) in [("a", 1.0), ("b", 2.0), ("c", 3.0)] {
+        persistence.log_entry(add(key, at, at)).unwrap();
+        let snapshot = persistence.begin_snapshot(&replay(&dir).unwrap()).unwrap();
+        finish(&mut persistence, &snapshot);
+    }
+    persistence.log_entry(add("d", 4.0, 4.0)).unwrap();
+    drop(persistence);
+
+    // The two newest snapshots are kept, with the WAL segment the older one
+    // needs to catch up.
+    assert_eq!(
+        files(&dir, "snapshot."),
+        vec!["snapshot.2.bincode", "snapshot.3.bincode"]
+    );
+    assert_eq!(rotated(&dir), vec!["wal.log.3"]);
+
+    // A damaged snapshot is passed over for the one before it.
+    let newest = dir.join("snapshot.3.bincode");
+    let mut bytes = fs::read(&newest).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    fs::write(&newest, &bytes).unwrap();
+    assert_eq!(keys(&replay(&dir).unwrap()), vec!["a", "b", "c", "d"]);
+
+    // With none left to load, recovery stops rather than start empty.
+    fs::write(dir.join("snapshot.2.bincode"), b"GEOMMSNP").unwrap();
+    let error = replay(&dir).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains("no snapshot"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_snapshot_rules() {
+    assert_eq!(
+        SnapshotRule::parse_rules("900 1  300 10"),
+        Some(vec![
+            SnapshotRule {
+                after: Duration::from_secs(900),
+                changes: 1,
+            },
+            SnapshotRule {
+                after: Duration::from_secs(300),
+                changes: 10,
+            },
+        ])
+    );
+    assert_eq!(SnapshotRule::parse_rules(""), Some(vec![]));
+    assert_eq!(SnapshotRule::parse_rules("900"), None);
+    assert_eq!(SnapshotRule::parse_rules("900 one"), None);
+
+    let dir = data_dir("rules");
+    let mut persistence = Persistence::open(DataDir::lock(&dir).unwrap()).unwrap();
+    let rules = SnapshotRule::parse_rules("0 2 3600 1").unwrap();
+    assert!(!persistence.snapshot_due(&rules));
+    persistence.log_entry(add("a", 1.0, 1.0)).unwrap();
+    assert!(!persistence.snapshot_due(&rules));
+    persistence.log_entry(add("b", 2.0, 2.0)).unwrap();
+    assert!(persistence.snapshot_due(&rules));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn info(persistence: &Mutex<Persistence>, name: &str) -> String {
+    let info = persistence.lock().unwrap().info();
+    info.into_iter()
+        .find(|(field, _)| *field == name)
+        .unwrap()
+        .1
+}
+
+#[tokio::test]
+async fn test_background_save() {
+    let dir = data_dir("bgsave");
+    write_wal(&dir, vec![add("a", 1.0, 1.0)]);
+    let collections = Mutex::new(replay(&dir).unwrap());
+    let persistence = Arc::new(Mutex::new(
+        Persistence::open(DataDir::lock(&dir).unwrap()).unwrap(),
+    ));
+    assert_eq!(info(&persistence, "changes_since_last_save"), "1");
+    assert_eq!(info(&persistence, "last_snapshot_status"), "none");
+
+    // The snapshot is a copy of the collections as they were when it began,
+    // whatever is written while it is on its way to disk.
+    persistence::background_save(&collections, &persistence).unwrap();
+    {
+        let mut collections = collections.lock().unwrap();
+        let mut persistence = persistence.lock().unwrap();
+        let entry = add("b", 2.0, 2.0);
+        if let WalEntry::GeoAdd { key, geometry, .. } = &entry {
+            collections
+                .geo_add_if("places", key.clone(), geometry.clone(), None)
+                .unwrap();
+        }
+        persistence.log_entry(entry).unwrap();
+    }
+    while info(&persistence, "snapshot_in_progress") == "1" {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(info(&persistence, "last_snapshot_status"), "ok");
+    assert_eq!(info(&persistence, "last_snapshot_seq"), "1");
+    assert_eq!(info(&persistence, "changes_since_last_save"), "1");
+    assert_eq!(keys(&replay(&dir).unwrap()), vec!["a", "b"]);
+    let copy = data_dir("bgsave-copy");
+    fs::copy(
+        dir.join("snapshot.1.bincode"),
+        copy.join("snapshot.1.bincode"),
+    )
+    .unwrap();
+    assert_eq!(keys(&replay(&copy).unwrap()), vec!["a"]);
+    fs::remove_dir_all(&copy).unwrap();
+
+    // A save waits for the snapshot to be written.
+    assert_eq!(persistence::save(&collections, &persistence).unwrap(), 2);
+    assert_eq!(info(&persistence, "changes_since_last_save"), "0");
+    assert_eq!(
+        files(&dir, "snapshot."),
+        vec!["snapshot.1.bincode", "snapshot.2.bincode"]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_data_dir_lock() {
+    let dir = data_dir("lock").join("node");
+    let data_dir = DataDir::lock(&dir).unwrap();
+    assert!(dir.join("geommdb.lock").exists());
+
+    // A second node on the same directory is turned away until the first
+    // lets go of it.
+    let error = DataDir::lock(&dir).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::ResourceBusy);
+    assert!(error.to_string().contains("in use by another process"));
+    let mut persistence = Persistence::open(data_dir).unwrap();
+    persistence.log_entry(add("a", 1.0, 1.0)).unwrap();
+    assert!(DataDir::lock(&dir).is_err());
+    drop(persistence);
+
+    write_wal(&dir, vec![add("b", 2.0, 2.0)]);
+    assert_eq!(keys(&replay(&dir).unwrap()), vec!["a", "b"]);
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_open_recovered() {
+    let dir = data_dir("open-recovered");
+    write_wal(&dir, vec![add("a", 1.0, 1.0), add("b", 2.0, 2.0)]);
+    let mut persistence = open(&dir, 3);
+    let snapshot = persistence.begin_snapshot(&replay(&dir).unwrap()).unwrap();
+    finish(&mut persistence, &snapshot);
+    persistence.log_entry(add("c", 3.0, 3.0)).unwrap();
+    drop(persistence);
+
+    // Opening after recovery carries on where the WAL that was replayed
+    // left off, just as opening it from scratch does.
+    let position = |persistence: &Mutex<Persistence>| {
+        ["wal_seq", "last_snapshot_seq", "changes_since_last_save"]
+            .map(|name| info(persistence, name))
+    };
+    let opened = Mutex::new(Persistence::open(DataDir::lock(&dir).unwrap()).unwrap());
+    assert_eq!(position(&opened), ["3", "2", "1"]);
+    drop(opened);
+    let data_dir = DataDir::lock(&dir).unwrap();
+    let recovery = Persistence::recover(data_dir.path()).unwrap();
+    assert_eq!(keys(&recovery.collections), vec!["a", "b", "c"]);
+    let recovered = Mutex::new(Persistence::open_recovered(data_dir, &recovery).unwrap());
+    assert_eq!(position(&recovered), ["3", "2", "1"]);
+    recovered
+        .lock()
+        .unwrap()
+        .log_entry(add("d", 4.0, 4.0))
+        .unwrap();
+    drop(recovered);
+    assert_eq!(keys(&replay(&dir).unwrap()), vec!["a", "b", "c", "d"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
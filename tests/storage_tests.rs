@@ -0,0 +1,1102 @@
+use geo::{Contains, Geometry};
+use geommdb::collections::{unshare, GeoCollections, UnknownCollection};
+use geommdb::geospatial::{
+    geohash_cell, geometry_from_coords, geometry_from_geohash, geometry_from_wkt, geometry_geohash,
+    geometry_position, geometry_type, polygon_from_lat_lon, polygon_from_wkt, AxisOrder,
+    DistanceMethod, DistanceUnit,
+};
+use geommdb::h3::{geometry_cell, PolyfillError};
+use geommdb::properties::{parse_properties, properties_to_json, CompareOp, Filter, PropValue};
+use geommdb::storage::{
+    expiry_deadline, now_ms, validate_polygon, AddCondition, AddOutcome, AreaMatch, BoxMatch,
+    GeoDatabase, GeometryError, KeyTtl, QueryPlan, SearchOptions, SortOrder,
+};
+use h3o::{CellIndex, LatLng, Resolution};
+use std::sync::Mutex;
+use std::time::Duration;
+
+fn point(lat: f64, lon: f64) -> Geometry<f64> {
+    geometry_from_coords(&[(lat, lon)]).unwrap()
+}
+
+fn polygon(ring: &[(f64, f64)]) -> Geometry<f64> {
+    geometry_from_coords(ring).unwrap()
+}
+
+#[test]
+fn test_geo_del_removes_point_from_search() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("point1".to_string(), point(40.7128, -74.0060))
+        .unwrap();
+    db.geo_add("point2".to_string(), point(40.7130, -74.0062))
+        .unwrap();
+
+    assert!(db.geo_del("point1"));
+    assert!(!db.geo_del("point1"));
+    assert_eq!(db.geo_get("point1", AxisOrder::LatLon), None);
+    assert_eq!(db.geo_search(40.7128, -74.0060, 100.0), vec!["point2"]);
+}
+
+#[test]
+fn test_geo_del_removes_polygon() {
+    let mut db = GeoDatabase::new();
+    db.geo_add(
+        "polygon1".to_string(),
+        polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)]),
+    )
+    .unwrap();
+
+    assert!(db.geo_del("polygon1"));
+    assert_eq!(db.geo_get("polygon1", AxisOrder::LatLon), None);
+    assert!(db.geo_search(0.5, 0.5, 1.0).is_empty());
+}
+
+#[test]
+fn test_geo_add_overwrites_previous_geometry() {
+    let mut db = GeoDatabase::new();
+    assert_eq!(
+        db.geo_add("vehicle".to_string(), point(40.7128, -74.0060))
+            .unwrap(),
+        AddOutcome::Created
+    );
+    assert_eq!(
+        db.geo_add("vehicle".to_string(), point(34.0522, -118.2437))
+            .unwrap(),
+        AddOutcome::Updated
+    );
+
+    assert!(db.geo_search(40.7128, -74.0060, 1000.0).is_empty());
+    assert_eq!(db.geo_search(34.0522, -118.2437, 1000.0), vec!["vehicle"]);
+
+    // Changing the geometry type must not leave the key in both maps.
+    db.geo_add(
+        "vehicle".to_string(),
+        polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (0.0, 0.0)]),
+    )
+    .unwrap();
+    assert!(db
+        .geo_get("vehicle", AxisOrder::LatLon)
+        .unwrap()
+        .starts_with("POLYGON"));
+    assert!(db.geo_del("vehicle"));
+    assert!(!db.contains_key("vehicle"));
+}
+
+#[test]
+fn test_geo_add_conditions() {
+    let mut db = GeoDatabase::new();
+    let coords = point(40.7128, -74.0060);
+
+    assert_eq!(
+        db.geo_add_if(
+            "point1".to_string(),
+            coords.clone(),
+            Some(AddCondition::IfExists)
+        )
+        .unwrap(),
+        AddOutcome::Skipped
+    );
+    assert!(!db.contains_key("point1"));
+    assert_eq!(
+        db.geo_add_if(
+            "point1".to_string(),
+            coords.clone(),
+            Some(AddCondition::IfNotExists)
+        )
+        .unwrap(),
+        AddOutcome::Created
+    );
+    assert_eq!(
+        db.geo_add_if(
+            "point1".to_string(),
+            coords.clone(),
+            Some(AddCondition::IfNotExists)
+        )
+        .unwrap(),
+        AddOutcome::Skipped
+    );
+    assert_eq!(
+        db.geo_add_if("point1".to_string(), coords, Some(AddCondition::IfExists))
+            .unwrap(),
+        AddOutcome::Updated
+    );
+}
+
+#[test]
+fn test_geo_search_returns_every_key_sharing_coordinates() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("driver1".to_string(), point(40.7128, -74.0060))
+        .unwrap();
+    db.geo_add("driver2".to_string(), point(40.7128, -74.0060))
+        .unwrap();
+
+    let mut results = db.geo_search(40.7128, -74.0060, 10.0);
+    results.sort();
+    assert_eq!(results, vec!["driver1", "driver2"]);
+
+    db.geo_del("driver1");
+    assert_eq!(db.geo_search(40.7128, -74.0060, 10.0), vec!["driver2"]);
+}
+
+#[test]
+fn test_geo_search_across_antimeridian() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("east".to_string(), point(0.0, 179.999)).unwrap();
+    db.geo_add("west".to_string(), point(0.0, -179.999))
+        .unwrap();
+
+    let mut results = db.geo_search(0.0, 180.0, 1000.0);
+    results.sort();
+    assert_eq!(results, vec!["east", "west"]);
+}
+
+#[test]
+fn test_geo_search_polygons_by_geodesic_distance() {
+    let mut db = GeoDatabase::new();
+    // A one-degree square; its eastern edge runs along longitude 1.
+    db.geo_add(
+        "zone".to_string(),
+        polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)]),
+    )
+    .unwrap();
+
+    // Inside the polygon the distance is zero, whatever the radius.
+    assert_eq!(db.geo_search(0.5, 0.5, 1.0), vec!["zone"]);
+    // About 556 m east of the edge.
+    assert!(db.geo_search(0.5, 1.005, 500.0).is_empty());
+    assert_eq!(db.geo_search(0.5, 1.005, 600.0), vec!["zone"]);
+    // Far away, where a radius in degrees would still have matched.
+    assert!(db.geo_search(3.0, 3.0, 500.0).is_empty());
+}
+
+#[test]
+fn test_geo_nearest_orders_by_haversine_distance() {
+    let mut db = GeoDatabase::new();
+    // At 60° north a degree of longitude is half as long as a degree of
+    // latitude, so "east" is closer even though it is further in degrees.
+    db.geo_add("north".to_string(), point(60.6, 10.0)).unwrap();
+    db.geo_add("east".to_string(), point(60.0, 10.9)).unwrap();
+    db.geo_add("far".to_string(), point(65.0, 10.0)).unwrap();
+
+    let hits = db.geo_nearest(60.0, 10.0, 2, None);
+    let keys: Vec<&str> = hits.iter().map(|hit| hit.key.as_str()).collect();
+    assert_eq!(keys, vec!["east", "north"]);
+    assert!(hits
+        .windows(2)
+        .all(|pair| pair[0].distance <= pair[1].distance));
+
+    let hits = db.geo_nearest(60.0, 10.0, 10, Some(60_000.0));
+    let keys: Vec<&str> = hits.iter().map(|hit| hit.key.as_str()).collect();
+    assert_eq!(keys, vec!["east"]);
+
+    // A centre that cannot be measured from matches nothing.
+    assert!(db.geo_nearest(f64::NAN, 10.0, 2, None).is_empty());
+    assert!(db.geo_nearest(60.0, 200.0, 2, None).is_empty());
+}
+
+#[test]
+fn test_geo_nearest_includes_polygons() {
+    let mut db = GeoDatabase::new();
+    db.geo_add(
+        "zone".to_string(),
+        polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)]),
+    )
+    .unwrap();
+    db.geo_add("point".to_string(), point(0.5, 0.6)).unwrap();
+
+    let hits = db.geo_nearest(0.5, 0.5, 2, None);
+    assert_eq!(hits[0].key, "zone");
+    assert_eq!(hits[0].distance, 0.0);
+    assert_eq!(hits[1].key, "point");
+}
+
+#[test]
+fn test_geo_search_box() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("inside".to_string(), point(0.5, 0.5)).unwrap();
+    db.geo_add("outside".to_string(), point(5.0, 5.0)).unwrap();
+    db.geo_add(
+        "straddling".to_string(),
+        polygon(&[(0.8, 0.8), (0.8, 1.5), (1.5, 1.5), (1.5, 0.8), (0.8, 0.8)]),
+    )
+    .unwrap();
+    db.geo_add(
+        "contained".to_string(),
+        polygon(&[(0.1, 0.1), (0.1, 0.2), (0.2, 0.2), (0.2, 0.1), (0.1, 0.1)]),
+    )
+    .unwrap();
+
+    let mut results = db
+        .geo_search_box(0.0, 0.0, 1.0, 1.0, BoxMatch::Intersects)
+        .unwrap();
+    results.sort();
+    assert_eq!(results, vec!["contained", "inside", "straddling"]);
+
+    let mut results = db
+        .geo_search_box(0.0, 0.0, 1.0, 1.0, BoxMatch::Within)
+        .unwrap();
+    results.sort();
+    assert_eq!(results, vec!["contained", "inside"]);
+
+    assert_eq!(
+        db.geo_search_box(1.0, 0.0, 0.0, 1.0, BoxMatch::Intersects),
+        Err(GeometryError::InvertedBox {
+            min_lat: 1.0,
+            max_lat: 0.0
+        })
+    );
+    assert_eq!(
+        db.geo_search_box(0.0, 0.0, 91.0, 1.0, BoxMatch::Intersects),
+        Err(GeometryError::LatitudeOutOfRange(91.0))
+    );
+    assert_eq!(
+        db.geo_search_box(0.0, -181.0, 1.0, 1.0, BoxMatch::Intersects),
+        Err(GeometryError::LongitudeOutOfRange(-181.0))
+    );
+    assert_eq!(
+        db.geo_search_box(0.0, 0.0, f64::NAN, 1.0, BoxMatch::Intersects),
+        Err(GeometryError::NotFinite)
+    );
+}
+
+#[test]
+fn test_geo_search_box_across_antimeridian() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("east".to_string(), point(10.0, 179.5)).unwrap();
+    db.geo_add("west".to_string(), point(10.0, -179.5)).unwrap();
+    db.geo_add("greenwich".to_string(), point(10.0, 0.0))
+        .unwrap();
+
+    let mut results = db
+        .geo_search_box(0.0, 179.0, 20.0, -179.0, BoxMatch::Intersects)
+        .unwrap();
+    results.sort();
+    assert_eq!(results, vec!["east", "west"]);
+}
+
+#[test]
+fn test_geo_containing() {
+    let mut db = GeoDatabase::new();
+    db.geo_add(
+        "big".to_string(),
+        polygon(&[(0.0, 0.0), (0.0, 2.0), (2.0, 2.0), (2.0, 0.0), (0.0, 0.0)]),
+    )
+    .unwrap();
+    // A triangle whose bounding box covers (1.5, 1.5) although the shape does not.
+    db.geo_add(
+        "triangle".to_string(),
+        polygon(&[(0.0, 0.0), (0.0, 2.0), (2.0, 0.0), (0.0, 0.0)]),
+    )
+    .unwrap();
+
+    assert_eq!(db.geo_containing(1.5, 1.5), vec!["big"]);
+    let mut results = db.geo_containing(0.5, 0.5);
+    results.sort();
+    assert_eq!(results, vec!["big", "triangle"]);
+    assert!(db.geo_containing(3.0, 3.0).is_empty());
+}
+
+#[test]
+fn test_geo_search_area() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("inside".to_string(), point(1.0, 1.0)).unwrap();
+    db.geo_add("outside".to_string(), point(5.0, 5.0)).unwrap();
+    db.geo_add(
+        "small".to_string(),
+        polygon(&[(0.5, 0.5), (0.5, 1.0), (1.0, 1.0), (1.0, 0.5), (0.5, 0.5)]),
+    )
+    .unwrap();
+    db.geo_add(
+        "overlapping".to_string(),
+        polygon(&[(1.5, 1.5), (1.5, 2.5), (2.5, 2.5), (2.5, 1.5), (1.5, 1.5)]),
+    )
+    .unwrap();
+    db.geo_add(
+        "enclosing".to_string(),
+        polygon(&[
+            (-1.0, -1.0),
+            (-1.0, 3.0),
+            (3.0, 3.0),
+            (3.0, -1.0),
+            (-1.0, -1.0),
+        ]),
+    )
+    .unwrap();
+
+    let area = polygon_from_wkt("POLYGON((0 0, 0 2, 2 2, 2 0, 0 0))", AxisOrder::LatLon).unwrap();
+    assert_eq!(
+        area,
+        polygon_from_lat_lon(&[(0.0, 0.0), (0.0, 2.0), (2.0, 2.0), (2.0, 0.0)])
+    );
+
+    let mut results = db.geo_search_area(&area, AreaMatch::Within);
+    results.sort();
+    assert_eq!(results, vec!["inside", "small"]);
+
+    let mut results = db.geo_search_area(&area, AreaMatch::Intersects);
+    results.sort();
+    assert_eq!(results, vec!["enclosing", "inside", "overlapping", "small"]);
+
+    assert_eq!(
+        db.geo_search_area(&area, AreaMatch::Contains),
+        vec!["enclosing"]
+    );
+}
+
+#[test]
+fn test_line_strings_and_multi_geometries() {
+    let mut db = GeoDatabase::new();
+    // A road along the equator and a country made of two islands.
+    db.geo_add(
+        "road".to_string(),
+        geometry_from_wkt("LINESTRING(0 0, 0 2)", AxisOrder::LatLon).unwrap(),
+    )
+    .unwrap();
+    db.geo_add(
+        "islands".to_string(),
+        geometry_from_wkt("MULTIPOLYGON(((10 10, 10 11, 11 11, 11 10, 10 10)), ((20 20, 20 21, 21 21, 21 20, 20 20)))", AxisOrder::LatLon)
+            .unwrap(),
+    ).unwrap();
+
+    // About 111 m north of the road.
+    assert_eq!(db.geo_search(0.001, 1.0, 200.0), vec!["road"]);
+    assert!(db.geo_search(0.01, 1.0, 200.0).is_empty());
+    // A road is not a geofence.
+    assert!(db.geo_containing(0.0, 1.0).is_empty());
+
+    assert_eq!(db.geo_containing(10.5, 10.5), vec!["islands"]);
+    assert_eq!(db.geo_containing(20.5, 20.5), vec!["islands"]);
+    assert!(db.geo_containing(15.0, 15.0).is_empty());
+
+    assert_eq!(
+        db.geo_get("road", AxisOrder::LatLon).unwrap(),
+        "LINESTRING(0 0,0 2)"
+    );
+}
+
+#[test]
+fn test_polygon_with_hole() {
+    let mut db = GeoDatabase::new();
+    db.geo_add(
+        "ring".to_string(),
+        geometry_from_wkt(
+            "POLYGON((0 0, 0 4, 4 4, 4 0, 0 0), (1 1, 1 3, 3 3, 3 1, 1 1))",
+            AxisOrder::LatLon,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(db.geo_containing(0.5, 0.5), vec!["ring"]);
+    assert!(db.geo_containing(2.0, 2.0).is_empty());
+    assert!(db.geo_search(2.0, 2.0, 1000.0).is_empty());
+}
+
+#[test]
+fn test_axis_order() {
+    let mut db = GeoDatabase::new();
+    // A thin sliver north of the equator: inside at lat 1, lon 10, but the
+    // swapped point (lat 10, lon 1) is far outside.
+    db.geo_add(
+        "sliver".to_string(),
+        polygon(&[(0.0, 9.0), (0.0, 11.0), (2.0, 11.0), (2.0, 9.0), (0.0, 9.0)]),
+    )
+    .unwrap();
+    assert_eq!(db.geo_containing(1.0, 10.0), vec!["sliver"]);
+    assert!(db.geo_containing(10.0, 1.0).is_empty());
+
+    let lon_lat = geometry_from_wkt("POINT(10 1)", AxisOrder::LonLat).unwrap();
+    assert_eq!(lon_lat, point(1.0, 10.0));
+    db.geo_add("poi".to_string(), lon_lat).unwrap();
+    assert_eq!(db.geo_get("poi", AxisOrder::LatLon).unwrap(), "POINT(1 10)");
+    assert_eq!(db.geo_get("poi", AxisOrder::LonLat).unwrap(), "POINT(10 1)");
+    assert!(db.geo_search(1.0, 10.0, 1.0).contains(&"poi".to_string()));
+    assert!(db.geo_search(10.0, 1.0, 1.0).is_empty());
+}
+
+#[test]
+fn test_geometry_validation() {
+    let mut db = GeoDatabase::new();
+    let wkt = |wkt: &str| geometry_from_wkt(wkt, AxisOrder::LatLon);
+
+    assert_eq!(geometry_from_coords(&[]), Err(GeometryError::Empty));
+    assert_eq!(
+        db.geo_add("far".to_string(), point(200.0, 0.0)),
+        Err(GeometryError::LatitudeOutOfRange(200.0))
+    );
+    assert_eq!(
+        db.geo_add("far".to_string(), point(0.0, -181.0)),
+        Err(GeometryError::LongitudeOutOfRange(-181.0))
+    );
+    assert_eq!(
+        db.geo_add("nan".to_string(), point(f64::NAN, 0.0)),
+        Err(GeometryError::NotFinite)
+    );
+    assert_eq!(
+        geometry_from_coords(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0)]),
+        Err(GeometryError::UnclosedRing)
+    );
+    assert_eq!(
+        wkt("POLYGON((0 0, 0 1, 1 1, 0 0.5))"),
+        Err(GeometryError::UnclosedRing)
+    );
+    assert_eq!(
+        db.geo_add(
+            "sliver".to_string(),
+            polygon(&[(0.0, 0.0), (0.0, 1.0), (0.0, 0.0)])
+        ),
+        Err(GeometryError::RingTooShort)
+    );
+    assert_eq!(
+        db.geo_add("dot".to_string(), wkt("LINESTRING(1 1, 1 1)").unwrap()),
+        Err(GeometryError::LineTooShort)
+    );
+    // A bowtie: its two diagonals cross in the middle.
+    assert_eq!(
+        db.geo_add(
+            "bowtie".to_string(),
+            polygon(&[(0.0, 0.0), (1.0, 1.0), (1.0, 0.0), (0.0, 1.0), (0.0, 0.0)])
+        ),
+        Err(GeometryError::SelfIntersection)
+    );
+    assert_eq!(
+        db.geo_add(
+            "adrift".to_string(),
+            wkt("POLYGON((0 0, 0 1, 1 1, 1 0, 0 0), (5 5, 5 6, 6 6, 6 5, 5 5))").unwrap()
+        ),
+        Err(GeometryError::HoleOutsideShell)
+    );
+    assert_eq!(
+        db.geo_add(
+            "nested".to_string(),
+            wkt("POLYGON((0 0, 0 9, 9 9, 9 0, 0 0), (1 1, 1 8, 8 8, 8 1, 1 1), (2 2, 2 3, 3 3, 3 2, 2 2))")
+                .unwrap()
+        ),
+        Err(GeometryError::NestedHole)
+    );
+    assert!(db
+        .geo_add(
+            "holes".to_string(),
+            wkt("POLYGON((0 0, 0 9, 9 9, 9 0, 0 0), (1 1, 1 2, 2 2, 2 1, 1 1), (5 5, 5 6, 6 6, 6 5, 5 5))")
+                .unwrap()
+        )
+        .is_ok());
+    assert_eq!(
+        db.geo_add(
+            "overlapping".to_string(),
+            wkt("MULTIPOLYGON(((0 0, 0 2, 2 2, 2 0, 0 0)), ((1 1, 1 3, 3 3, 3 1, 1 1)))").unwrap()
+        ),
+        Err(GeometryError::OverlappingParts)
+    );
+    assert_eq!(
+        db.geo_add(
+            "island".to_string(),
+            wkt("MULTIPOLYGON(((0 0, 0 9, 9 9, 9 0, 0 0)), ((1 1, 1 2, 2 2, 2 1, 1 1)))").unwrap()
+        ),
+        Err(GeometryError::OverlappingParts)
+    );
+    assert!(db
+        .geo_add(
+            "touching".to_string(),
+            wkt("MULTIPOLYGON(((0 0, 0 1, 1 1, 1 0, 0 0)), ((1 0, 1 1, 2 1, 2 0, 1 0)))").unwrap()
+        )
+        .is_ok());
+    assert_eq!(wkt("NOT WKT"), Err(GeometryError::InvalidWkt));
+    assert_eq!(
+        wkt("GEOMETRYCOLLECTION(POINT(1 1))"),
+        Err(GeometryError::UnsupportedType)
+    );
+    assert!(validate_polygon(&polygon_from_lat_lon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0)])).is_ok());
+    assert!(!db.contains_key("far") && !db.contains_key("bowtie"));
+    assert!(!db.contains_key("nested") && !db.contains_key("overlapping"));
+}
+
+#[test]
+fn test_properties() {
+    let mut db = GeoDatabase::new();
+    let properties =
+        parse_properties(r#"{"name": "Cafe", "rating": 4.5, "open": true, "tags": ["wifi"]}"#)
+            .unwrap();
+    assert_eq!(properties["rating"], PropValue::Number(4.5));
+    assert!(parse_properties("[1, 2]").is_none());
+    assert!(!db.geo_set_props("cafe", properties.clone()));
+
+    db.geo_add("cafe".to_string(), point(40.7128, -74.0060))
+        .unwrap();
+    assert!(db.geo_set_props("cafe", properties.clone()));
+    assert_eq!(
+        properties_to_json(db.geo_props("cafe").unwrap()).to_string(),
+        r#"{"name":"Cafe","open":true,"rating":4.5,"tags":["wifi"]}"#
+    );
+
+    // Moving the key keeps its properties; they survive a snapshot round trip.
+    db.geo_add("cafe".to_string(), point(40.7130, -74.0062))
+        .unwrap();
+    let db: GeoDatabase = bincode::deserialize(&bincode::serialize(&db).unwrap()).unwrap();
+    assert_eq!(db.geo_props("cafe"), Some(&properties));
+
+    let mut db = db;
+    db.geo_del("cafe");
+    db.geo_add("cafe".to_string(), point(40.7128, -74.0060))
+        .unwrap();
+    assert_eq!(db.geo_props("cafe"), None);
+}
+
+fn filter(field: &str, op: CompareOp, value: PropValue) -> Filter {
+    Filter {
+        field: field.to_string(),
+        op,
+        value,
+    }
+}
+
+#[test]
+fn test_filtered_search_with_secondary_indexes() {
+    let mut db = GeoDatabase::new();
+    // A grid of restaurants near the origin; every tenth one is rated 5.
+    for i in 0..100 {
+        let key = format!("restaurant{}", i);
+        let (lat, lon) = ((i / 10) as f64 * 0.01, (i % 10) as f64 * 0.01);
+        db.geo_add(key.clone(), point(lat, lon)).unwrap();
+        let rating = if i % 10 == 9 { 5 } else { 3 };
+        let json = format!(r#"{{"rating": {}, "open": {}}}"#, rating, i % 20 != 19);
+        db.geo_set_props(&key, parse_properties(&json).unwrap());
+    }
+    // Far away, but otherwise a perfect match.
+    db.geo_add("remote".to_string(), point(10.0, 10.0)).unwrap();
+    db.geo_set_props(
+        "remote",
+        parse_properties(r#"{"rating": 5, "open": true}"#).unwrap(),
+    );
+
+    let filters = vec![
+        filter("rating", CompareOp::Ge, PropValue::Number(5.0)),
+        filter("open", CompareOp::Eq, PropValue::Bool(true)),
+    ];
+    let search = |db: &GeoDatabase| {
+        let mut results = db.geo_search_where(0.0, 0.0, 500_000.0, &filters);
+        results.sort();
+        results
+    };
+    let spatial = search(&db);
+    assert_eq!(
+        db.plan_search(0.0, 0.0, 500_000.0, &filters),
+        QueryPlan::Spatial
+    );
+    assert_eq!(
+        spatial,
+        vec![
+            "restaurant29",
+            "restaurant49",
+            "restaurant69",
+            "restaurant89",
+            "restaurant9"
+        ]
+    );
+
+    assert!(db.create_index("rating"));
+    assert!(!db.create_index("rating"));
+    // 11 keys are rated 5, fewer than the search box is expected to hold.
+    assert_eq!(
+        db.plan_search(0.0, 0.0, 500_000.0, &filters),
+        QueryPlan::Index {
+            field: "rating".to_string()
+        }
+    );
+    assert_eq!(search(&db), spatial);
+    // A tight radius makes the spatial side cheaper again.
+    assert_eq!(
+        db.plan_search(0.0, 0.0, 100.0, &filters),
+        QueryPlan::Spatial
+    );
+
+    // The index follows property updates and deletes.
+    db.geo_set_props(
+        "restaurant9",
+        parse_properties(r#"{"rating": 1, "open": true}"#).unwrap(),
+    );
+    db.geo_del("restaurant29");
+    assert_eq!(
+        search(&db),
+        vec!["restaurant49", "restaurant69", "restaurant89"]
+    );
+
+    // Filters on a property of another type never match.
+    let mismatched = [filter(
+        "rating",
+        CompareOp::Lt,
+        PropValue::String("9".to_string()),
+    )];
+    assert!(db
+        .geo_search_where(0.0, 0.0, 500_000.0, &mismatched)
+        .is_empty());
+
+    assert!(db.drop_index("rating"));
+    assert!(db.indexed_fields().is_empty());
+    assert_eq!(
+        search(&db),
+        vec!["restaurant49", "restaurant69", "restaurant89"]
+    );
+}
+
+#[test]
+fn test_set_props_keeps_unchanged_indexed_value() {
+    let mut db = GeoDatabase::new();
+    db.create_index("cuisine");
+    // Enough other keys nearby that the search starts from the index.
+    for i in 1..10 {
+        let key = format!("pizzeria{}", i);
+        db.geo_add(key.clone(), point(0.0, i as f64 * 0.001))
+            .unwrap();
+        db.geo_set_props(&key, parse_properties(r#"{"cuisine": "pizza"}"#).unwrap());
+    }
+    db.geo_add("cafe".to_string(), point(0.0, 0.0)).unwrap();
+    let props = r#"{"cuisine": "thai", "open": true}"#;
+    db.geo_set_props("cafe", parse_properties(props).unwrap());
+    // Only the other field changes; the indexed value stays the same.
+    let props = r#"{"cuisine": "thai", "open": false}"#;
+    db.geo_set_props("cafe", parse_properties(props).unwrap());
+
+    let filters = [filter(
+        "cuisine",
+        CompareOp::Eq,
+        PropValue::String("thai".to_string()),
+    )];
+    assert_eq!(
+        db.plan_search(0.0, 0.0, 10_000.0, &filters),
+        QueryPlan::Index {
+            field: "cuisine".to_string()
+        }
+    );
+    assert_eq!(
+        db.geo_search_where(0.0, 0.0, 10_000.0, &filters),
+        vec!["cafe"]
+    );
+}
+
+#[test]
+fn test_expiry() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("courier".to_string(), point(40.7128, -74.0060))
+        .unwrap();
+    db.geo_add(
+        "zone".to_string(),
+        polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (0.0, 0.0)]),
+    )
+    .unwrap();
+    db.geo_add("depot".to_string(), point(40.7130, -74.0062))
+        .unwrap();
+    assert_eq!(db.geo_ttl("courier"), KeyTtl::Persistent);
+    assert_eq!(db.geo_ttl("missing"), KeyTtl::Missing);
+    assert!(!db.geo_expire_at("missing", now_ms()));
+
+    assert!(db.geo_expire_at("depot", now_ms() + 60_000));
+    match db.geo_ttl("depot") {
+        KeyTtl::ExpiresIn(remaining) => assert!(remaining > Duration::from_secs(59)),
+        ttl => panic!("unexpected ttl {:?}", ttl),
+    }
+    // Deadlines past what a u64 holds are refused rather than wrapped.
+    assert_eq!(expiry_deadline(u64::MAX / 1000), None);
+    assert_eq!(expiry_deadline(u64::MAX), None);
+
+    // Expired keys vanish from reads before the sweeper gets to them.
+    assert!(db.geo_expire_at("courier", now_ms() - 1));
+    assert!(db.geo_expire_at("zone", now_ms() - 1));
+    assert!(!db.contains_key("courier"));
+    assert_eq!(db.geo_get("courier", AxisOrder::LatLon), None);
+    assert_eq!(db.geo_ttl("courier"), KeyTtl::Missing);
+    assert_eq!(db.geo_search(40.7128, -74.0060, 1000.0), vec!["depot"]);
+    assert!(db.geo_containing(0.5, 0.2).is_empty());
+    let nearest = db.geo_nearest(40.7128, -74.0060, 5, None);
+    assert_eq!(nearest.len(), 1);
+    assert_eq!(nearest[0].key, "depot");
+
+    let mut purged = db.purge_expired(now_ms());
+    purged.sort();
+    assert_eq!(purged, vec!["courier", "zone"]);
+    assert!(db.purge_expired(now_ms()).is_empty());
+
+    // Re-adding a key clears its expiry.
+    db.geo_add("depot".to_string(), point(40.7130, -74.0062))
+        .unwrap();
+    assert_eq!(db.geo_ttl("depot"), KeyTtl::Persistent);
+}
+
+#[test]
+fn test_collections() {
+    let mut collections = GeoCollections::new();
+    assert!(collections.create_collection("stores"));
+    assert!(!collections.create_collection("stores"));
+
+    // Adding to a missing collection creates it; the same key can live in both.
+    let outcome = collections
+        .geo_add_if("vehicles", "v1".to_string(), point(40.7128, -74.0060), None)
+        .unwrap();
+    assert_eq!(outcome, AddOutcome::Created);
+    let outcome = collections
+        .geo_add_if("stores", "v1".to_string(), point(40.7130, -74.0062), None)
+        .unwrap();
+    assert_eq!(outcome, AddOutcome::Created);
+    assert_eq!(collections.collection_names(), vec!["stores", "vehicles"]);
+
+    // A rejected or skipped add does not leave an empty collection behind.
+    assert!(collections
+        .geo_add_if("broken", "b".to_string(), point(91.0, 0.0), None)
+        .is_err());
+    let outcome = collections
+        .geo_add_if(
+            "skipped",
+            "s".to_string(),
+            point(0.0, 0.0),
+            Some(AddCondition::IfExists),
+        )
+        .unwrap();
+    assert_eq!(outcome, AddOutcome::Skipped);
+    assert_eq!(
+        collections.get("broken").unwrap_err(),
+        UnknownCollection("broken".to_string())
+    );
+    assert!(collections.get("skipped").is_err());
+
+    // Searches and indexes are per collection.
+    let stores = collections.get_mut("stores").unwrap();
+    stores
+        .geo_add("s2".to_string(), point(40.7129, -74.0061))
+        .unwrap();
+    assert!(stores.create_index("rating"));
+    let mut results = collections
+        .get("stores")
+        .unwrap()
+        .geo_search(40.7128, -74.0060, 1000.0);
+    results.sort();
+    assert_eq!(results, vec!["s2", "v1"]);
+    let vehicles = collections.get("vehicles").unwrap();
+    assert_eq!(vehicles.geo_search(40.7128, -74.0060, 1000.0), vec!["v1"]);
+    assert!(vehicles.indexed_fields().is_empty());
+
+    // Each collection survives a snapshot round trip on its own.
+    let mut collections: GeoCollections =
+        bincode::deserialize(&bincode::serialize(&collections).unwrap()).unwrap();
+    assert_eq!(
+        collections.get("stores").unwrap().indexed_fields(),
+        vec!["rating"]
+    );
+
+    assert!(collections.drop_collection("stores"));
+    assert!(!collections.drop_collection("stores"));
+    assert_eq!(collections.collection_names(), vec!["vehicles"]);
+}
+
+#[test]
+fn test_unshare() {
+    let mut collections = GeoCollections::new();
+    collections
+        .geo_add_if("stores", "s1".to_string(), point(40.7128, -74.0060), None)
+        .unwrap();
+    let collections = Mutex::new(collections);
+    let stores =
+        |collections: &GeoCollections| collections.get("stores").unwrap() as *const GeoDatabase;
+
+    // Nothing else holds the collection, so it stays where it is.
+    let before = stores(&collections.lock().unwrap());
+    unshare(&collections, "stores");
+    unshare(&collections, "missing");
+    assert_eq!(stores(&collections.lock().unwrap()), before);
+
+    // A snapshot's copy keeps the old collection; writes go to a new one.
+    let snapshot = collections.lock().unwrap().clone();
+    unshare(&collections, "stores");
+    let mut collections = collections.into_inner().unwrap();
+    assert_ne!(stores(&collections), stores(&snapshot));
+    let copy = stores(&collections);
+    collections
+        .get_mut("stores")
+        .unwrap()
+        .geo_add("s2".to_string(), point(40.7129, -74.0061))
+        .unwrap();
+    assert_eq!(stores(&collections), copy);
+    assert_eq!(snapshot.key_count(), 1);
+    assert_eq!(collections.key_count(), 2);
+}
+
+#[test]
+fn test_search_options() {
+    let mut db = GeoDatabase::new();
+    // Stops due north of the origin, roughly 1.1 km apart.
+    for i in 1..=5 {
+        db.geo_add(format!("stop{}", i), point(i as f64 * 0.01, 0.0))
+            .unwrap();
+    }
+    db.geo_add(
+        "block".to_string(),
+        polygon(&[
+            (-0.015, -0.01),
+            (-0.015, 0.01),
+            (-0.025, 0.01),
+            (-0.015, -0.01),
+        ]),
+    )
+    .unwrap();
+    let search = |options: SearchOptions| {
+        db.geo_search_hits(0.0, 0.0, 10_000.0, &[], &options)
+            .into_iter()
+            .map(|hit| hit.key)
+            .collect::<Vec<_>>()
+    };
+
+    let hits = db.geo_search_hits(
+        0.0,
+        0.0,
+        10_000.0,
+        &[],
+        &SearchOptions {
+            order: Some(SortOrder::Asc),
+            ..SearchOptions::default()
+        },
+    );
+    let keys: Vec<&str> = hits.iter().map(|hit| hit.key.as_str()).collect();
+    assert_eq!(
+        keys,
+        vec!["stop1", "block", "stop2", "stop3", "stop4", "stop5"]
+    );
+    assert!((hits[0].distance - 1111.95).abs() < 0.1);
+    assert!(hits
+        .windows(2)
+        .all(|pair| pair[0].distance <= pair[1].distance));
+
+    let desc = search(SearchOptions {
+        order: Some(SortOrder::Desc),
+        ..SearchOptions::default()
+    });
+    assert_eq!(desc.first().map(String::as_str), Some("stop5"));
+
+    // COUNT alone keeps the closest hits, with DESC the farthest.
+    let closest = search(SearchOptions {
+        count: Some(2),
+        ..SearchOptions::default()
+    });
+    assert_eq!(closest, vec!["stop1", "block"]);
+    let farthest = search(SearchOptions {
+        order: Some(SortOrder::Desc),
+        count: Some(2),
+        any: false,
+    });
+    assert_eq!(farthest, vec!["stop5", "stop4"]);
+
+    // ANY stops at the first matches found, whichever they are.
+    let any = search(SearchOptions {
+        count: Some(3),
+        any: true,
+        ..SearchOptions::default()
+    });
+    assert_eq!(any.len(), 3);
+
+    assert_eq!(geometry_type(db.geo_geometry("block").unwrap()), "POLYGON");
+    assert_eq!(
+        geometry_position(db.geo_geometry("block").unwrap()),
+        vec![(-0.025, -0.01), (-0.015, 0.01)]
+    );
+    assert_eq!(
+        geometry_position(db.geo_geometry("stop1").unwrap()),
+        vec![(0.01, 0.0)]
+    );
+}
+
+#[test]
+fn test_distance_units() {
+    assert_eq!("km".parse(), Ok(DistanceUnit::Kilometres));
+    assert_eq!("NMI".parse(), Ok(DistanceUnit::NauticalMiles));
+    assert_eq!("yd".parse::<DistanceUnit>(), Err(()));
+    assert_eq!(DistanceUnit::Miles.to_metres(1.0), 1609.344);
+    assert_eq!(DistanceUnit::Feet.from_metres(0.3048), 1.0);
+    assert_eq!(DistanceUnit::Kilometres.from_metres(2500.0), 2.5);
+
+    let mut db = GeoDatabase::new();
+    // About 11.1 km north of the origin, just over 6 nautical miles.
+    db.geo_add("buoy".to_string(), point(0.1, 0.0)).unwrap();
+    let radius = DistanceUnit::NauticalMiles.to_metres(6.1);
+    assert_eq!(db.geo_search(0.0, 0.0, radius), vec!["buoy"]);
+    let radius = DistanceUnit::Miles.to_metres(6.0);
+    assert!(db.geo_search(0.0, 0.0, radius).is_empty());
+
+    let hit = &db.geo_nearest(0.0, 0.0, 1, None)[0];
+    assert!((DistanceUnit::Kilometres.from_metres(hit.distance) - 11.1195).abs() < 1e-4);
+}
+
+#[test]
+fn test_geo_dist() {
+    assert_eq!("Geodesic".parse(), Ok(DistanceMethod::Geodesic));
+    assert_eq!("euclidean".parse::<DistanceMethod>(), Err(()));
+
+    let mut db = GeoDatabase::new();
+    db.geo_add("a".to_string(), point(0.0, 0.0)).unwrap();
+    db.geo_add("b".to_string(), point(0.0, 1.0)).unwrap();
+    db.geo_add("antipode".to_string(), point(0.5, 179.7))
+        .unwrap();
+    db.geo_add(
+        "square".to_string(),
+        polygon(&[(0.0, 2.0), (0.0, 3.0), (1.0, 3.0), (1.0, 2.0), (0.0, 2.0)]),
+    )
+    .unwrap();
+    db.geo_add(
+        "overlap".to_string(),
+        polygon(&[(0.5, 2.5), (0.5, 4.0), (2.0, 4.0), (2.0, 2.5), (0.5, 2.5)]),
+    )
+    .unwrap();
+
+    // One degree along the equator is a sixth of a kilometre shorter on the
+    // haversine sphere than on the WGS84 ellipsoid.
+    let dist = |key1, key2, method| db.geo_dist(key1, key2, method).unwrap().unwrap();
+    assert!((dist("a", "b", DistanceMethod::Haversine) - 111_195.08).abs() < 0.01);
+    assert!((dist("a", "b", DistanceMethod::Geodesic) - 111_319.49).abs() < 0.01);
+    assert!((dist("a", "b", DistanceMethod::Vincenty) - 111_319.49).abs() < 0.01);
+
+    // Point to polygon measures to the nearest edge, in either order.
+    let to_square = dist("b", "square", DistanceMethod::Geodesic);
+    assert!((to_square - 111_319.49).abs() < 0.01);
+    assert_eq!(dist("square", "b", DistanceMethod::Geodesic), to_square);
+
+    assert_eq!(dist("square", "overlap", DistanceMethod::Geodesic), 0.0);
+    assert!(dist("a", "overlap", DistanceMethod::Geodesic) > 2.0 * to_square);
+
+    assert!(db
+        .geo_dist("a", "antipode", DistanceMethod::Vincenty)
+        .unwrap()
+        .is_err());
+    assert!(dist("a", "antipode", DistanceMethod::Geodesic) > 19_900_000.0);
+    assert!(db
+        .geo_dist("a", "missing", DistanceMethod::Haversine)
+        .is_none());
+}
+
+#[test]
+fn test_geohash() {
+    let stored = point(35.3003, -120.6623);
+    assert_eq!(geometry_geohash(&stored, 10).unwrap(), "9q60y60rhs");
+    assert_eq!(geometry_geohash(&stored, 5).unwrap(), "9q60y");
+    assert!(geometry_geohash(&stored, 13).is_err());
+
+    // A polygon is hashed by its centroid.
+    let square = polygon(&[(0.0, 0.0), (0.0, 2.0), (2.0, 2.0), (2.0, 0.0), (0.0, 0.0)]);
+    assert_eq!(
+        geometry_geohash(&square, 8).unwrap(),
+        geometry_geohash(&point(1.0, 1.0), 8).unwrap()
+    );
+
+    // A geohash stands for the centre of its cell, and case does not matter.
+    let centre = geometry_from_geohash("9Q60Y60RHS").unwrap();
+    assert_eq!(geometry_geohash(&centre, 10).unwrap(), "9q60y60rhs");
+    let cell = geohash_cell("9q60y60rhs").unwrap();
+    assert!(cell.width() < 2e-5 && cell.height() < 2e-5);
+
+    assert_eq!(
+        geohash_cell("9q6a"),
+        Err(GeometryError::InvalidGeohash("9q6a".to_string()))
+    );
+    assert!(geohash_cell("").is_err());
+    assert!(geohash_cell("9q60y60rhs9q6").is_err());
+
+    // Anything inside or overlapping the cell matches its prefix.
+    let mut db = GeoDatabase::new();
+    db.geo_add(
+        "inside".to_string(),
+        geometry_from_geohash("s00twy").unwrap(),
+    )
+    .unwrap();
+    db.geo_add("overlapping".to_string(), square).unwrap();
+    db.geo_add("outside".to_string(), point(-1.0, -1.0))
+        .unwrap();
+    let cell = geohash_cell("s00").unwrap();
+    let mut results = db
+        .geo_search_box(
+            cell.min().y,
+            cell.min().x,
+            cell.max().y,
+            cell.max().x,
+            BoxMatch::Intersects,
+        )
+        .unwrap();
+    results.sort();
+    assert_eq!(results, vec!["inside", "overlapping"]);
+}
+
+#[test]
+fn test_h3_index() {
+    let mut db = GeoDatabase::new();
+    db.geo_add("a".to_string(), point(37.7749, -122.4194))
+        .unwrap();
+    db.geo_add("b".to_string(), point(37.7750, -122.4195))
+        .unwrap();
+    db.geo_add("far".to_string(), point(40.7128, -74.0060))
+        .unwrap();
+
+    let cell = LatLng::new(37.7749, -122.4194)
+        .unwrap()
+        .to_cell(Resolution::Nine);
+    assert_eq!(db.geo_h3_cell("a", Resolution::Nine), Some(cell));
+    assert_eq!(db.geo_h3_cell("missing", Resolution::Nine), None);
+    assert_eq!(db.geo_h3_keys(cell, 0), None);
+
+    assert!(db.create_h3_index(Resolution::Nine));
+    assert!(!db.create_h3_index(Resolution::Nine));
+    assert_eq!(db.h3_resolution(), Some(Resolution::Nine));
+    assert_eq!(db.geo_h3_keys(cell, 0).unwrap(), vec!["a", "b"]);
+
+    // Re-adding a key in the same cell keeps it indexed.
+    db.geo_add("b".to_string(), point(37.7750, -122.4195))
+        .unwrap();
+    assert_eq!(db.geo_h3_keys(cell, 0).unwrap(), vec!["a", "b"]);
+
+    // Coarser cells cover their descendants, finer ones narrow things down.
+    let region = cell.parent(Resolution::Four).unwrap();
+    assert_eq!(db.geo_h3_keys(region, 0).unwrap(), vec!["a", "b"]);
+    let fine = db.geo_h3_cell("a", Resolution::Fifteen).unwrap();
+    assert_eq!(db.geo_h3_keys(fine, 0).unwrap(), vec!["a"]);
+
+    // A key one cell over only shows up in the k-ring.
+    let neighbour: CellIndex = cell.grid_disk::<Vec<_>>(1)[1];
+    let centre = LatLng::from(neighbour);
+    db.geo_add("next".to_string(), point(centre.lat(), centre.lng()))
+        .unwrap();
+    assert_eq!(db.geo_h3_keys(cell, 0).unwrap(), vec!["a", "b"]);
+    assert_eq!(db.geo_h3_keys(cell, 1).unwrap(), vec!["a", "b", "next"]);
+
+    db.geo_del("a");
+    assert_eq!(db.geo_h3_keys(cell, 0).unwrap(), vec!["b"]);
+
+    // Moving to another resolution rebuilds the index.
+    assert!(db.create_h3_index(Resolution::Two));
+    let far = db.geo_h3_cell("far", Resolution::Two).unwrap();
+    assert_eq!(db.geo_h3_keys(far, 0).unwrap(), vec!["far"]);
+    assert!(db.drop_h3_index());
+    assert!(!db.drop_h3_index());
+}
+
+#[test]
+fn test_h3_polyfill() {
+    let mut db = GeoDatabase::new();
+    let square = polygon(&[(0.0, 0.0), (0.0, 0.1), (0.1, 0.1), (0.1, 0.0), (0.0, 0.0)]);
+    db.geo_add("square".to_string(), square.clone()).unwrap();
+    db.geo_add("pin".to_string(), point(0.05, 0.05)).unwrap();
+
+    let cells = db
+        .geo_h3_polyfill("square", Resolution::Eight)
+        .unwrap()
+        .unwrap();
+    assert!(cells.len() > 100);
+    for cell in &cells {
+        let centre = LatLng::from(*cell);
+        assert!(square.contains(&geo::Point::new(centre.lng(), centre.lat())));
+    }
+    assert!(cells.contains(&geometry_cell(&square, Resolution::Eight)));
+
+    assert_eq!(
+        db.geo_h3_polyfill("pin", Resolution::Eight),
+        Some(Err(PolyfillError::NotAPolygon))
+    );
+    assert!(matches!(
+        db.geo_h3_polyfill("square", Resolution::Fifteen),
+        Some(Err(PolyfillError::TooManyCells(_)))
+    ));
+    assert_eq!(db.geo_h3_polyfill("missing", Resolution::Eight), None);
+}